@@ -0,0 +1,134 @@
+//! Client credentials flow, authenticating with a certificate instead of a secret
+//!
+//! The Application proves possession of the certificate's private key by presenting a signed
+//! JWT client assertion. The assertion is an RS256-signed JWT whose header carries the
+//! certificate's SHA-1 thumbprint (`x5t`), which is how AAD finds the certificate registered
+//! with the application. See
+//! https://learn.microsoft.com/en-us/entra/identity-platform/certificate-credentials
+
+extern crate openssl;
+extern crate serde_json;
+
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use token_cache::AccessToken;
+use super::{JWT_BEARER, TokenEndpoint, TokenProvider, client_credentials_cache_key,
+            request_client_credentials_token};
+use self::openssl::base64;
+use self::openssl::hash::MessageDigest;
+use self::openssl::pkey::PKey;
+use self::openssl::rand::rand_bytes;
+use self::openssl::sign::Signer;
+use self::openssl::x509::X509;
+use std::fs::File;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long (in seconds) an assertion is valid. It is used immediately, so this can be short.
+const ASSERTION_LIFETIME: u64 = 600;
+
+pub struct Certificate<'a> {
+    token_endpoint: TokenEndpoint<'a>,
+    client_id: &'a str,
+    /// Paths to PEM files; they may name the same file
+    certificate: &'a str,
+    private_key: &'a str,
+}
+
+#[derive(Serialize)]
+struct Header<'a> {
+    alg: &'a str,
+    typ: &'a str,
+    x5t: String,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    aud: &'a str,
+    iss: &'a str,
+    sub: &'a str,
+    jti: String,
+    nbf: u64,
+    exp: u64,
+}
+
+/// Encode `data` as unpadded base64url, as JWTs require.
+fn base64url(data: &[u8]) -> String {
+    base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+/// Read a PEM file in its entirety.
+fn read_pem(path: &str) -> GraphInfoResult<Vec<u8>> {
+    let mut contents = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .map_err(|_| GraphInfoRetrievalError::BadClientCertificate)?;
+    Ok(contents)
+}
+
+impl<'a> Certificate<'a> {
+    pub fn new(token_endpoint: TokenEndpoint<'a>,
+               client_id: &'a str,
+               certificate: &'a str,
+               private_key: &'a str)
+               -> Certificate<'a> {
+        Certificate {
+            token_endpoint,
+            client_id,
+            certificate,
+            private_key,
+        }
+    }
+
+    /// Build a client assertion, to be presented to the token endpoint at `audience`.
+    fn build_assertion(&self, audience: &str) -> GraphInfoResult<String> {
+        let cert = X509::from_pem(&read_pem(self.certificate)?)?;
+        let key = PKey::private_key_from_pem(&read_pem(self.private_key)?)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| GraphInfoRetrievalError::BadClientCertificate)?
+            .as_secs();
+        let mut jti = [0u8; 16];
+        rand_bytes(&mut jti)?;
+
+        let header = Header {
+            alg: "RS256",
+            typ: "JWT",
+            x5t: base64url(&cert.digest(MessageDigest::sha1())?),
+        };
+        let claims = Claims {
+            aud: audience,
+            iss: self.client_id,
+            sub: self.client_id,
+            jti: jti.iter().map(|b| format!("{:02x}", b)).collect(),
+            nbf: now,
+            exp: now + ASSERTION_LIFETIME,
+        };
+        let signing_input = format!("{}.{}",
+                                    base64url(serde_json::to_string(&header)?.as_bytes()),
+                                    base64url(serde_json::to_string(&claims)?.as_bytes()));
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(signing_input.as_bytes())?;
+        let signature = signer.sign_to_vec()?;
+
+        Ok(format!("{}.{}", signing_input, base64url(&signature)))
+    }
+}
+
+impl<'a> TokenProvider for Certificate<'a> {
+    fn cache_key(&self) -> String {
+        client_credentials_cache_key(&self.token_endpoint, self.client_id)
+    }
+
+    fn request_token(&self) -> GraphInfoResult<AccessToken> {
+        let assertion = self.build_assertion(&self.token_endpoint.token_url())?;
+        request_client_credentials_token(&self.token_endpoint,
+                                         self.client_id,
+                                         &[("client_assertion_type", JWT_BEARER),
+                                           ("client_assertion", &assertion)])
+    }
+}
@@ -0,0 +1,37 @@
+//! Client credentials flow, authenticating with the Application's client secret
+
+use error::GraphInfoResult;
+use token_cache::AccessToken;
+use super::{TokenEndpoint, TokenProvider, client_credentials_cache_key,
+            request_client_credentials_token};
+
+pub struct ClientSecret<'a> {
+    token_endpoint: TokenEndpoint<'a>,
+    client_id: &'a str,
+    client_secret: &'a str,
+}
+
+impl<'a> ClientSecret<'a> {
+    pub fn new(token_endpoint: TokenEndpoint<'a>,
+               client_id: &'a str,
+               client_secret: &'a str)
+               -> ClientSecret<'a> {
+        ClientSecret {
+            token_endpoint,
+            client_id,
+            client_secret,
+        }
+    }
+}
+
+impl<'a> TokenProvider for ClientSecret<'a> {
+    fn cache_key(&self) -> String {
+        client_credentials_cache_key(&self.token_endpoint, self.client_id)
+    }
+
+    fn request_token(&self) -> GraphInfoResult<AccessToken> {
+        request_client_credentials_token(&self.token_endpoint,
+                                         self.client_id,
+                                         &[("client_secret", self.client_secret)])
+    }
+}
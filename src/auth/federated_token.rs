@@ -0,0 +1,59 @@
+//! Workload identity federation: the client credentials flow, authenticating with a token
+//! issued by an identity provider that the Application trusts (e.g. a projected Kubernetes
+//! service account token).
+
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use std::fs::File;
+use std::io::Read;
+use token_cache::AccessToken;
+use super::{JWT_BEARER, TokenEndpoint, TokenProvider, client_credentials_cache_key,
+            request_client_credentials_token};
+
+pub struct FederatedToken<'a> {
+    token_endpoint: TokenEndpoint<'a>,
+    client_id: &'a str,
+    token_file: &'a str,
+}
+
+impl<'a> FederatedToken<'a> {
+    pub fn new(token_endpoint: TokenEndpoint<'a>,
+               client_id: &'a str,
+               token_file: &'a str)
+               -> FederatedToken<'a> {
+        FederatedToken {
+            token_endpoint,
+            client_id,
+            token_file,
+        }
+    }
+
+    /// Read the federated token from the token file
+    ///
+    /// The file is rotated underneath us by whatever projects it, so it is read afresh every
+    /// time a token is requested rather than being kept around.
+    fn read_token(&self) -> GraphInfoResult<String> {
+        let mut token = String::new();
+        File::open(self.token_file)
+            .and_then(|mut f| f.read_to_string(&mut token))
+            .map_err(|_| GraphInfoRetrievalError::BadFederatedToken)?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(GraphInfoRetrievalError::BadFederatedToken);
+        }
+        Ok(token.to_string())
+    }
+}
+
+impl<'a> TokenProvider for FederatedToken<'a> {
+    fn cache_key(&self) -> String {
+        client_credentials_cache_key(&self.token_endpoint, self.client_id)
+    }
+
+    fn request_token(&self) -> GraphInfoResult<AccessToken> {
+        let assertion = self.read_token()?;
+        request_client_credentials_token(&self.token_endpoint,
+                                         self.client_id,
+                                         &[("client_assertion_type", JWT_BEARER),
+                                           ("client_assertion", &assertion)])
+    }
+}
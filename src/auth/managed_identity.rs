@@ -0,0 +1,65 @@
+//! Tokens for an Azure VM's managed identity, from the Instance Metadata Service (IMDS)
+
+extern crate hyper;
+extern crate url;
+
+use azure;
+use error::GraphInfoResult;
+use self::hyper::header::Headers;
+use self::url::form_urlencoded;
+use token_cache::AccessToken;
+use super::{TokenEndpoint, TokenProvider, extract_token};
+
+/// The managed identity endpoint of IMDS.
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+const IMDS_API_VERSION: &str = "2018-02-01";
+
+pub struct ManagedIdentity<'a> {
+    /// IMDS issues tokens for the Graph endpoint of the cloud that the VM is in
+    token_endpoint: TokenEndpoint<'a>,
+    /// The client ID of a user-assigned identity, or None for the system-assigned identity
+    client_id: Option<&'a str>,
+    /// Overrides the IMDS token endpoint, e.g. to point at a local stand-in
+    endpoint: Option<&'a str>,
+}
+
+impl<'a> ManagedIdentity<'a> {
+    pub fn new(token_endpoint: TokenEndpoint<'a>,
+               client_id: Option<&'a str>,
+               endpoint: Option<&'a str>)
+               -> ManagedIdentity<'a> {
+        ManagedIdentity {
+            token_endpoint,
+            client_id,
+            endpoint,
+        }
+    }
+}
+
+impl<'a> TokenProvider for ManagedIdentity<'a> {
+    fn cache_key(&self) -> String {
+        format!("managed-identity/{}/{}",
+                self.client_id.unwrap_or("system"),
+                self.token_endpoint.scope())
+    }
+
+    /// IMDS only answers requests that carry a `Metadata: true` header, which protects it from
+    /// server-side request forgery. It predates scopes, so it is asked for a resource instead.
+    fn request_token(&self) -> GraphInfoResult<AccessToken> {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("api-version", IMDS_API_VERSION);
+        query.append_pair("resource", self.token_endpoint.graph);
+        if let Some(client_id) = self.client_id {
+            query.append_pair("client_id", client_id);
+        }
+        let token_url = format!("{}?{}",
+                                self.endpoint.unwrap_or(IMDS_TOKEN_URL),
+                                query.finish());
+
+        let mut headers = Headers::new();
+        headers.set_raw("Metadata", vec![b"true".to_vec()]);
+        let token_json = azure::get_content(&token_url, Some(headers))?;
+
+        extract_token(&token_json)
+    }
+}
@@ -0,0 +1,234 @@
+//! Sources of OAuth2 access tokens for the Graph API
+//!
+//! Each way of authenticating is a `TokenProvider`, in a module of its own. `AadConfig` selects
+//! one with its `auth` section, and `get_access_token` takes care of caching the tokens that
+//! the provider issues.
+
+extern crate serde_json;
+
+mod certificate;
+mod client_secret;
+mod federated_token;
+mod managed_identity;
+mod static_token;
+
+use AadConfig;
+use azure;
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use self::serde_json::Value;
+use token_cache::{self, AccessToken};
+
+pub use self::certificate::Certificate;
+pub use self::client_secret::ClientSecret;
+pub use self::federated_token::FederatedToken;
+pub use self::managed_identity::ManagedIdentity;
+pub use self::static_token::StaticToken;
+
+/// The `client_assertion_type` that accompanies a JWT client assertion.
+const JWT_BEARER: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Where tokens are requested from, and for which resource.
+#[derive(Clone,Copy)]
+pub struct TokenEndpoint<'a> {
+    authority: &'a str,
+    tenant: &'a str,
+    graph: &'a str,
+}
+
+impl<'a> TokenEndpoint<'a> {
+    /// The tenant's OAuth2 v2.0 token endpoint.
+    fn token_url(&self) -> String {
+        format!("{}/{}/oauth2/v2.0/token", self.authority, self.tenant)
+    }
+
+    /// The OAuth2 scope that grants the application permissions configured for Microsoft Graph.
+    fn scope(&self) -> String {
+        format!("{}/.default", self.graph)
+    }
+}
+
+/// A source of access tokens.
+pub trait TokenProvider {
+    /// A string identifying the tokens this provider issues, under which they are cached. It
+    /// must differ between providers whose tokens are not interchangeable, and must not contain
+    /// anything secret.
+    fn cache_key(&self) -> String;
+
+    /// Obtain a new token. This is only called when there is no usable token in the cache.
+    fn request_token(&self) -> GraphInfoResult<AccessToken>;
+}
+
+/// The `auth` section of the configuration file, naming a `method` and its parameters.
+#[derive(Deserialize,Debug)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum AuthConfig {
+    ClientSecret {
+        client_id: String,
+        client_secret: String,
+    },
+    Certificate {
+        client_id: String,
+        certificate: String,
+        #[serde(default)]
+        private_key: Option<String>,
+    },
+    ManagedIdentity {
+        #[serde(default)]
+        client_id: Option<String>,
+        #[serde(default)]
+        endpoint: Option<String>,
+    },
+    FederatedToken {
+        client_id: String,
+        token_file: String,
+    },
+    StaticToken { token: String },
+}
+
+impl AadConfig {
+    /// Build the TokenProvider selected by this configuration.
+    ///
+    /// Configurations without an `auth` section fall back to the top-level `client_id` and
+    /// `client_secret`, as earlier versions of this plugin required.
+    pub fn token_provider<'a>(&'a self) -> GraphInfoResult<Box<dyn TokenProvider + 'a>> {
+        let token_endpoint = TokenEndpoint {
+            authority: self.authority(),
+            tenant: &self.tenant,
+            graph: self.graph_endpoint(),
+        };
+        Ok(match self.auth {
+               Some(AuthConfig::ClientSecret { ref client_id, ref client_secret }) => {
+                   Box::new(ClientSecret::new(token_endpoint, client_id, client_secret))
+               }
+               Some(AuthConfig::Certificate { ref client_id,
+                                              ref certificate,
+                                              ref private_key }) => {
+                   // The private key may live alongside the certificate, in the same PEM file
+                   let private_key = private_key.as_ref().unwrap_or(certificate);
+                   Box::new(Certificate::new(token_endpoint, client_id, certificate, private_key))
+               }
+               Some(AuthConfig::ManagedIdentity { ref client_id, ref endpoint }) => {
+                   Box::new(ManagedIdentity::new(token_endpoint,
+                                                 client_id.as_ref().map(|c| &c[..]),
+                                                 endpoint.as_ref().map(|e| &e[..])))
+               }
+               Some(AuthConfig::FederatedToken { ref client_id, ref token_file }) => {
+                   Box::new(FederatedToken::new(token_endpoint, client_id, token_file))
+               }
+               Some(AuthConfig::StaticToken { ref token }) => Box::new(StaticToken::new(token)),
+               None => {
+                   match (self.client_id.as_ref(), self.client_secret.as_ref()) {
+                       (Some(client_id), Some(client_secret)) => {
+                           Box::new(ClientSecret::new(token_endpoint, client_id, client_secret))
+                       }
+                       _ => return Err(GraphInfoRetrievalError::NoClientCredentials),
+                   }
+               }
+           })
+    }
+}
+
+/// Obtain an OAuth2 Bearer token using the TokenProvider selected by `config`
+///
+/// A token from an earlier call (in this process, or in another one via the token cache file) is
+/// reused for as long as it is valid; otherwise a new one is requested from the provider and
+/// cached for later calls.
+pub fn get_access_token(config: &AadConfig) -> GraphInfoResult<String> {
+    let provider = config.token_provider()?;
+    let cache_key = provider.cache_key();
+    if let Some(token) = token_cache::get(&cache_key) {
+        return Ok(token);
+    }
+    if let Some(ref path) = config.token_cache_file {
+        if let Some(token) = token_cache::get_from_file(path, &cache_key) {
+            token_cache::put(&cache_key, token.clone());
+            return Ok(token.token);
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    println!("libnss-aad::auth requesting a new access token");
+    let token = provider.request_token()?;
+    token_cache::put(&cache_key, token.clone());
+    if let Some(ref path) = config.token_cache_file {
+        token_cache::put_to_file(path, &cache_key, &token);
+    }
+
+    Ok(token.token)
+}
+
+/// The cache key for tokens obtained through the client credentials flow.
+fn client_credentials_cache_key(token_endpoint: &TokenEndpoint, client_id: &str) -> String {
+    format!("{}/{}/{}/{}",
+            token_endpoint.authority,
+            token_endpoint.tenant,
+            client_id,
+            token_endpoint.scope())
+}
+
+/// Request a token through the client credentials flow, authenticating with `credential` (the
+/// form parameters that carry the secret or assertion).
+fn request_client_credentials_token(token_endpoint: &TokenEndpoint,
+                                    client_id: &str,
+                                    credential: &[(&str, &str)])
+                                    -> GraphInfoResult<AccessToken> {
+    let scope = token_endpoint.scope();
+    let mut auth_params = vec![("scope", &scope[..]),
+                               ("grant_type", "client_credentials"),
+                               ("client_id", client_id)];
+    auth_params.extend_from_slice(credential);
+    let token_json = azure::post_query(&token_endpoint.token_url(), &auth_params)?;
+
+    extract_token(&token_json)
+}
+
+/// Extract the OAuth2 Bearer token, and its lifetime, from the provided JSON
+///
+/// The token endpoint has been seen to return `expires_in` both as a number and as a string, so
+/// both are accepted. A missing or unparseable `expires_in` yields a token that is already
+/// expired, which will be used once but never cached.
+///
+/// # Example
+///
+/// ```ignore
+/// let json: &str = "{\"access_token\": \"aaaabbbbccccdddd...\", \"expires_in\": \"3599\"}";
+/// assert_eq!(extract_token(json).unwrap().token, "aaaabbbbccccdddd...");
+/// ```
+fn extract_token(json: &str) -> GraphInfoResult<AccessToken> {
+    let response = serde_json::from_str::<Value>(json)?;
+    let token = response["access_token"]
+        .as_str()
+        .ok_or(GraphInfoRetrievalError::NoAccessToken { response: json.to_string() })?
+        .to_string();
+    let expires_in = match response["expires_in"] {
+        Value::Number(ref n) => n.as_u64().unwrap_or(0),
+        Value::String(ref s) => s.parse::<u64>().unwrap_or(0),
+        _ => 0,
+    };
+    Ok(AccessToken::new(token, expires_in))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_token;
+
+    #[test]
+    fn extract_token_accepts_expires_in_as_number_or_string() {
+        for json in &["{\"access_token\": \"abcd\", \"expires_in\": 3599}",
+                      "{\"access_token\": \"abcd\", \"expires_in\": \"3599\"}"] {
+            let token = extract_token(json).unwrap();
+            assert_eq!(token.token, "abcd");
+            assert!(token.is_fresh());
+        }
+    }
+
+    #[test]
+    fn extract_token_without_expires_in_is_already_stale() {
+        assert!(!extract_token("{\"access_token\": \"abcd\"}").unwrap().is_fresh());
+    }
+
+    #[test]
+    fn extract_token_requires_access_token() {
+        assert!(extract_token("{\"error\": \"invalid_client\"}").is_err());
+    }
+}
@@ -0,0 +1,29 @@
+//! A fixed bearer token taken from the configuration, for testing against a mock Graph server
+//! without a token endpoint.
+
+use error::GraphInfoResult;
+use token_cache::AccessToken;
+use super::TokenProvider;
+
+/// How long (in seconds) a static token is reported to be valid for.
+const STATIC_TOKEN_LIFETIME: u64 = 24 * 60 * 60;
+
+pub struct StaticToken<'a> {
+    token: &'a str,
+}
+
+impl<'a> StaticToken<'a> {
+    pub fn new(token: &'a str) -> StaticToken<'a> {
+        StaticToken { token }
+    }
+}
+
+impl<'a> TokenProvider for StaticToken<'a> {
+    fn cache_key(&self) -> String {
+        "static".to_string()
+    }
+
+    fn request_token(&self) -> GraphInfoResult<AccessToken> {
+        Ok(AccessToken::new(self.token.to_string(), STATIC_TOKEN_LIFETIME))
+    }
+}
@@ -1,6 +1,5 @@
 
 extern crate hyper;
-extern crate hyper_native_tls;
 extern crate serde_json;
 extern crate url;
 
@@ -8,24 +7,97 @@ use AadConfig;
 use UserInfo;
 use GroupInfo;
 
+use auth;
 use error::{GraphInfoResult, GraphInfoRetrievalError};
+use idmap::{ObjectKind, Reverse, ScanIndex};
+use names;
+use posix;
 use self::hyper::header::{Authorization, Bearer, Headers};
-use self::hyper::net::HttpsConnector;
-use self::hyper_native_tls::NativeTlsClient;
+use self::hyper::status::StatusCode;
+use self::hyper::net::{HttpStream, HttpsConnector, NetworkConnector};
+use tls::OpensslClient;
 use self::serde_json::Value;
 use self::url::form_urlencoded;
-use std::io::Read;
+use std::collections::HashSet;
+use std::io::{self, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
 
 type Query<'a> = Vec<(&'a str, &'a str)>;
 
+/// Microsoft Graph only returns a default set of properties, which does not include the
+/// on-premises SID, so every query names the properties it needs.
+const USER_PROPERTIES: &str = "id,userPrincipalName,displayName,\
+                               onPremisesSecurityIdentifier,onPremisesDomainName,\
+                               accountEnabled,lastPasswordChangeDateTime,\
+                               officeLocation,businessPhones";
+const GROUP_PROPERTIES: &str = "id,displayName,onPremisesSecurityIdentifier,\
+                                onPremisesDomainName";
+
+/// How long to wait for a connection to Graph or the token endpoint.
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+
+/// How long to wait for any one read or write, including those of the TLS handshake. This
+/// bounds each request rather than a whole lookup, so an enumeration that pages through the
+/// directory in many requests is not cut short by it.
+///
+/// A lookup makes a few requests, and all of them must fit well within the time that the
+/// plugin waits for the daemon (see `client`), or else the daemon would answer from the
+/// offline cache only after the plugin had given up.
+const IO_TIMEOUT_SECS: u64 = 10;
+
+/// `$expand` gives at most this many of a group's members. A group with this many may have
+/// more, which have to be asked for separately.
+const EXPANDED_MEMBERS_LIMIT: usize = 20;
+
+lazy_static! {
+    /// The UIDs and GIDs of every user and group, for IdMappers that can only find the object
+    /// with a given ID by examining every object
+    static ref USER_INDEX: ScanIndex = ScanIndex::new();
+    static ref GROUP_INDEX: ScanIndex = ScanIndex::new();
+    /// The POSIX ID attributes that Graph would not compare with an integer, and so hold their
+    /// IDs as strings
+    static ref STRING_ID_ATTRIBUTES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Makes TCP connections that give up after CONNECT_TIMEOUT_SECS, and whose reads and writes
+/// give up after IO_TIMEOUT_SECS. Without these, a connection to an unresponsive server would
+/// tie up one of the daemon's workers forever.
+struct TimeoutConnector;
+
+impl NetworkConnector for TimeoutConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, _scheme: &str) -> hyper::Result<HttpStream> {
+        let io_timeout = Some(Duration::from_secs(IO_TIMEOUT_SECS));
+        let mut err = io::Error::new(io::ErrorKind::NotFound,
+                                     format!("{} has no addresses", host));
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECT_TIMEOUT_SECS)) {
+                Ok(stream) => {
+                    stream.set_read_timeout(io_timeout)?;
+                    stream.set_write_timeout(io_timeout)?;
+                    return Ok(HttpStream(stream));
+                }
+                Err(e) => err = e,
+            }
+        }
+        Err(err.into())
+    }
+}
+
 fn get_ssl_client() -> hyper::Client {
-    let ssl = NativeTlsClient::new().unwrap();
-    let connector = HttpsConnector::new(ssl);
-    hyper::Client::with_connector(connector)
+    let ssl = OpensslClient::new().unwrap();
+    let connector = HttpsConnector::with_connector(ssl, TimeoutConnector);
+    let mut client = hyper::Client::with_connector(connector);
+    client.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)));
+    client
 }
 
 /// Issue an HTTPS POST request, and return the response body text
-fn post_query(url: &str, query: &Query) -> GraphInfoResult<String> {
+pub fn post_query(url: &str, query: &Query) -> GraphInfoResult<String> {
     let client = get_ssl_client();
     let body = form_urlencoded::Serializer::new(String::new())
         .extend_pairs(query.iter())
@@ -43,7 +115,7 @@ fn post_query(url: &str, query: &Query) -> GraphInfoResult<String> {
 }
 
 /// Issue an HTTPS GET request, and return the response body text.
-fn get_content(content_url: &str, headers: Option<Headers>) -> GraphInfoResult<String> {
+pub fn get_content(content_url: &str, headers: Option<Headers>) -> GraphInfoResult<String> {
     let client = get_ssl_client();
     let request = if let Some(h) = headers {
         client.get(content_url).headers(h)
@@ -62,26 +134,82 @@ fn get_content(content_url: &str, headers: Option<Headers>) -> GraphInfoResult<S
     Ok(buf)
 }
 
-/// Extract the OAuth2 Bearer token from the provided JSON
-///
-/// # Example
-///
-/// ```
-/// let json: &str = "{\"access_token\": \"aaaabbbbccccdddd...\"}";
-/// assert_eq!(extract_token(json).unwrap(), "aaaabbbbccccdddd....");
-/// ```
-fn extract_token(json: &str) -> GraphInfoResult<String> {
-    Ok(serde_json::from_str::<Value>(json)?["access_token"]
-           .as_str()
-           .ok_or(GraphInfoRetrievalError::NoAccessToken { response: json.to_string() })?
-           .to_string())
+/// The user properties to select: USER_PROPERTIES, plus the configured expiry and POSIX
+/// attributes and whatever the IdMapper needs.
+fn user_properties(config: &AadConfig) -> String {
+    let mut properties = USER_PROPERTIES.to_string();
+    if let Some(ref attribute) = config.shadow_expire_attribute {
+        add_property(&mut properties, attribute);
+    }
+    for attribute in config.posix_attributes.user.all() {
+        add_property(&mut properties, posix::property(attribute));
+    }
+    if let Some(property) = names::property(config) {
+        add_property(&mut properties, property);
+    }
+    add_property(&mut properties, config.id_mapper().property(ObjectKind::User));
+    properties
+}
+
+/// The group properties to select: GROUP_PROPERTIES, plus the configured POSIX attributes and
+/// whatever the IdMapper needs.
+fn group_properties(config: &AadConfig) -> String {
+    let mut properties = GROUP_PROPERTIES.to_string();
+    for attribute in config.posix_attributes.group.all() {
+        add_property(&mut properties, posix::property(attribute));
+    }
+    add_property(&mut properties, config.id_mapper().property(ObjectKind::Group));
+    properties
+}
+
+/// Percent-encode `s` for use as a path segment or query parameter of a Graph URL.
+pub fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+                 b'-' | b'.' | b'_' | b'~' | b'@' => (b as char).to_string(),
+                 _ if b.is_ascii_alphanumeric() => (b as char).to_string(),
+                 _ => format!("%{:02X}", b),
+             })
+        .collect()
+}
+
+/// Quote `s` as an OData string literal, for use in a `$filter`, and encode it for the URL.
+pub fn odata_string(s: &str) -> String {
+    url_encode(&format!("'{}'", s.replace('\'', "''")))
+}
+
+/// Add `property` to a comma-separated list of properties, unless it is already there.
+fn add_property(properties: &mut String, property: &str) {
+    if !properties.split(',').any(|p| p == property) {
+        properties.push(',');
+        properties.push_str(property);
+    }
+}
+
+/// Convert a Graph date/time (an ISO 8601 UTC timestamp, like `2017-04-01T12:00:00Z`) into a
+/// number of days since the epoch, as used by the shadow database.
+fn days_since_epoch(datetime: &str) -> Option<i64> {
+    let date = datetime.get(..10)?;
+    let mut parts = date.split('-').map(|p| p.parse::<i64>());
+    let (y, m, d) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(y)), Some(Ok(m)), Some(Ok(d))) if (1..=12).contains(&m) &&
+                                                    (1..=31).contains(&d) => (y, m, d),
+        _ => return None,
+    };
+    // Count from 1 March, so that the leap day falls at the end of the year
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y / 400; // Graph dates are all well after year 0
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146097 + doe - 719468)
 }
 
 /// Gather information out of the Graph API User json object.
 ///
 /// This should probably be obviated by having UserInfo derive Deserialize and using the (kind
 /// of ugly) attribute names that the Graph API uses.
-fn extract_user_info(userinfo: &Value) -> GraphInfoResult<UserInfo> {
+fn extract_user_info(config: &AadConfig, userinfo: &Value) -> GraphInfoResult<UserInfo> {
     let user_principal_name = userinfo["userPrincipalName"]
         .as_str()
         .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
@@ -90,21 +218,54 @@ fn extract_user_info(userinfo: &Value) -> GraphInfoResult<UserInfo> {
         .as_str()
         .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
         .to_string();
-    // was immutableId
-    let mut sid_parts : Vec<&str> = userinfo["onPremisesSecurityIdentifier"]
-        .as_str()
-        .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
-        .split('-').collect();
-    let user_id = sid_parts.pop().unwrap().parse::<u32>()?;
-    // rid < 1000 should only be built-in users
+    let posix_attributes = &config.posix_attributes.user;
+    let user_id = match posix::number(userinfo, &posix_attributes.uid_number)? {
+        Some(id) => id,
+        None => config.id_mapper().id_for(ObjectKind::User, userinfo)?,
+    };
+    // uid < 1000 should only be local system users
     if user_id < 1000 {
         return Err(GraphInfoRetrievalError::UnusableImmutableID);
     }
 
+    let groupid = posix::number(userinfo, &posix_attributes.gid_number)?;
+    // a primary gid < 1000 would make the user a member of a local system group
+    if groupid.is_some_and(|g| g < 1000) {
+        return Err(GraphInfoRetrievalError::UnusableImmutableID);
+    }
+
+    // accountEnabled is only missing if we may not read it, in which case assume the best
+    let enabled = userinfo["accountEnabled"].as_bool().unwrap_or(true);
+    let password_changed = userinfo["lastPasswordChangeDateTime"]
+        .as_str()
+        .and_then(days_since_epoch);
+    let expires = config
+        .shadow_expire_attribute
+        .as_ref()
+        .and_then(|attribute| userinfo[attribute.as_str()].as_str())
+        .and_then(days_since_epoch);
+
+    let object_id = userinfo["id"]
+        .as_str()
+        .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
+        .to_string();
+
     Ok(UserInfo {
-           username: user_principal_name,
+           username: names::present(config, userinfo, &user_principal_name),
+           office: userinfo["officeLocation"].as_str().map(|o| o.to_string()),
+           phone: userinfo["businessPhones"][0].as_str().map(|p| p.to_string()),
+           upn: user_principal_name,
+           object_id,
+           domain: userinfo["onPremisesDomainName"].as_str().map(|d| d.to_string()),
            fullname: user_display_name,
            userid: user_id,
+           enabled,
+           password_changed,
+           expires,
+           groupid,
+           shell: posix::string(userinfo, &posix_attributes.login_shell),
+           home: posix::string(userinfo, &posix_attributes.home_directory),
+           gecos: posix::string(userinfo, &posix_attributes.gecos),
        })
 }
 
@@ -112,68 +273,69 @@ fn extract_user_info(userinfo: &Value) -> GraphInfoResult<UserInfo> {
 ///
 /// This should probably be obviated by having GroupInfo derive Deserialize and using the (kind
 /// of ugly) attribute names that the Graph API uses.
-fn extract_group_info(group: &Value) -> GraphInfoResult<GroupInfo> {
+fn extract_group_info(config: &AadConfig, group: &Value) -> GraphInfoResult<GroupInfo> {
     let group_name = group["displayName"]
         .as_str()
         .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
         .to_string();
-    let object_id = group["objectId"]
+    let object_id = group["id"]
         .as_str()
         .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
         .to_string();
-    let mut sid_parts : Vec<&str> = group["onPremisesSecurityIdentifier"]
-        .as_str()
-        .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
-        .split('-').collect();
-    let group_id = sid_parts.pop().unwrap().parse::<u32>()?;
-    // rid < 1000 should only be built-in groups
+    let group_id = match posix::number(group, &config.posix_attributes.group.gid_number)? {
+        Some(id) => id,
+        None => config.id_mapper().id_for(ObjectKind::Group, group)?,
+    };
+    // gid < 1000 should only be local system groups
     if group_id < 1000 {
         return Err(GraphInfoRetrievalError::UnusableImmutableID);
     }
 
     Ok(GroupInfo {
            groupname: group_name,
-           object_id: object_id,
-           group_id: group_id,
+           object_id,
+           domain: group["onPremisesDomainName"].as_str().map(|d| d.to_string()),
+           group_id,
        })
 }
 
-/// Collects and returns UserInfo objects created from the raw results of a Graph API call.
-fn extract_group_members(json: &str) -> GraphInfoResult<Vec<UserInfo>> {
-    let values = &serde_json::from_str::<Value>(json)?["value"];
-    let members = values
-        .as_array()
-        .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
-        .into_iter()
-        .filter_map(|v| match extract_user_info(v) {
-            Ok(m) => Some(m),
-            Err(_) => None // we don't particularly care if we get a badly-formed json object
-        })
-        .collect::<Vec<UserInfo>>();
-    Ok(members)
-}
-
-/// Collects and returns GroupInfo objects created from the raw results of a Graph API call.
-fn extract_user_groups(json: &str) -> GraphInfoResult<Vec<GroupInfo>> {
-    let values = &serde_json::from_str::<Value>(json)?["value"];
-    if values.is_null() {
-        return Err(GraphInfoRetrievalError::NotFound);
+/// Sort the objects in a group's `members` collection into users and groups.
+///
+/// Anything else (devices, service principals and so on), and users that cannot be represented
+/// as passwd entries, have no place in a group entry and are left out.
+fn extract_group_members(config: &AadConfig, values: &[Value]) -> GroupMembers {
+    let mut members = GroupMembers {
+        users: vec![],
+        groups: vec![],
+    };
+    for v in values {
+        match v["@odata.type"].as_str() {
+            Some("#microsoft.graph.user") => {
+                match extract_user_info(config, v) {
+                    Ok(u) => members.users.push(u),
+                    Err(_e) => {
+                        #[cfg(debug_assertions)]
+                        println!("libnss-aad::azure skipping member {}: {:?}", v["id"], _e);
+                    }
+                }
+            }
+            Some("#microsoft.graph.group") => {
+                if let Some(id) = v["id"].as_str() {
+                    members.groups.push(id.to_string());
+                }
+            }
+            _ty => {
+                #[cfg(debug_assertions)]
+                println!("libnss-aad::azure skipping member {} of type {:?}", v["id"], _ty);
+            }
+        }
     }
-    let groups = values
-        .as_array()
-        .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
-        .into_iter()
-        .filter_map(|v| match extract_group_info(v) {
-                        Ok(g) => Some(g),
-                        Err(_) => None,
-                    })
-        .collect::<Vec<GroupInfo>>();
-    Ok(groups)
+    members
 }
 
-/// Extracts and returns the PageToken from a paged response.
+/// Extracts and returns the URL of the next page from a paged response.
 fn has_another_page(json: &str) -> GraphInfoResult<Option<String>> {
-    let link = &serde_json::from_str::<Value>(json)?["odata.nextLink"];
+    let link = &serde_json::from_str::<Value>(json)?["@odata.nextLink"];
     if link.is_null() {
         return Ok(None);
     }
@@ -184,40 +346,68 @@ fn has_another_page(json: &str) -> GraphInfoResult<Option<String>> {
 
 /// Fetch a UserInfo object for the named user
 pub fn get_user_info(config: &AadConfig, username: &str) -> GraphInfoResult<UserInfo> {
-    let query_url = &format!("https://graph.windows.net/{}/users/{}?api-version=1.6",
-                             config.tenant,
-                             username);
+    let query_url = &format!("{}/users/{}?$select={}",
+                             config.graph_url(),
+                             url_encode(username),
+                             user_properties(config));
     let info_json = get_graph_info(config, query_url)?;
     let user_info = &serde_json::from_str::<Value>(&info_json)?;
-    extract_user_info(user_info)
+    extract_user_info(config, user_info)
 }
 
-/// Fetch a UserInfo object for the provided sid
-pub fn get_user_info_by_sid(config: &AadConfig, sid: &str) -> GraphInfoResult<UserInfo> {
-    let query_url = &format!("https://graph.windows.net/{}/users?$filter=onPremisesSecurityIdentifier+eq+'{}'&api-version=1.6",
-                             config.tenant,
-                             sid);
-    let info_json = get_graph_info(config, query_url)?;
-    let values = &serde_json::from_str::<Value>(&info_json)?["value"];
-    let users = values
-        .as_array()
-        .ok_or(GraphInfoRetrievalError::BadJSONResponse)?;
-
-    if users.len() > 1 {
-        return Err(GraphInfoRetrievalError::TooManyResults);
-    }
-    if users.len() < 1 {
-        return Err(GraphInfoRetrievalError::NotFound);
+/// Fetch a UserInfo object for the user with the provided UID, which is either the user's
+/// uidNumber POSIX attribute or mapped by the configured IdMapper
+pub fn get_user_info_by_id(config: &AadConfig, uid: u32) -> GraphInfoResult<UserInfo> {
+    let mut users = match config.posix_attributes.user.uid_number {
+        Some(ref attribute) => {
+            find_by_posix_id(config, "users", &user_properties(config), attribute, uid)?
+                .iter()
+                .filter_map(|v| extract_user_info(config, v).ok())
+                .collect()
+        }
+        None => vec![],
+    };
+    if users.is_empty() {
+        users = match config.id_mapper().reverse(ObjectKind::User, uid)? {
+            Reverse::Equals { property, value } => {
+                find_users(config, &format!("{}+eq+{}", property, value))?
+            }
+            Reverse::IdAttribute { attribute } => {
+                find_by_posix_id(config, "users", &user_properties(config), &attribute, uid)?
+                    .iter()
+                    .filter_map(|v| extract_user_info(config, v).ok())
+                    .collect()
+            }
+            Reverse::Scan => {
+                let object_ids = USER_INDEX.find(uid, || {
+                    Ok(list_users(config)?
+                           .into_iter()
+                           .map(|u| (u.userid, u.object_id))
+                           .collect())
+                })?;
+                let mut users = vec![];
+                for object_id in object_ids {
+                    match get_user_info(config, &object_id) {
+                        Ok(u) => users.push(u),
+                        Err(ref e) if e.is_not_found() => {} // deleted since it was listed
+                        Err(e) => return Err(e),
+                    }
+                }
+                users
+            }
+            Reverse::Unmapped => vec![],
+        };
     }
-    extract_user_info(&users[0])
+    only_one(users.into_iter().filter(|u| u.userid == uid).collect())
 }
 
 /// Fetch a GroupInfo object for the named group
 pub fn get_group_info(config: &AadConfig, groupname: &str) -> GraphInfoResult<GroupInfo> {
     let group_info_json = get_graph_info(config,
-                                         &format!("https://graph.windows.net/{}/groups/?api-version=1.6&$filter=displayName+eq+'{}'",
-                                                  config.tenant,
-                                                  groupname))?;
+                                         &format!("{}/groups?$filter=displayName+eq+{}&$select={}",
+                                                  config.graph_url(),
+                                                  odata_string(groupname),
+                                                  group_properties(config)))?;
 
     let group_results = serde_json::from_str::<Value>(&group_info_json)?;
     let group_values = group_results["value"]
@@ -226,108 +416,393 @@ pub fn get_group_info(config: &AadConfig, groupname: &str) -> GraphInfoResult<Gr
     if group_values.len() > 1 {
         return Err(GraphInfoRetrievalError::TooManyResults);
     }
-    if group_values.len() < 1 {
+    if group_values.is_empty() {
         return Err(GraphInfoRetrievalError::NotFound);
     }
-    extract_group_info(&group_values[0])
+    extract_group_info(config, &group_values[0])
 }
 
-/// Fetch a GroupInfo object for the named group
-pub fn get_group_info_by_sid(config: &AadConfig, sid: &str) -> GraphInfoResult<GroupInfo> {
-    let query_url = &format!("https://graph.windows.net/{}/groups?$filter=onPremisesSecurityIdentifier+eq+'{}'&api-version=1.6",
-                             config.tenant,
-                             sid);
+/// Fetch a GroupInfo object for the group with the given object ID
+pub fn get_group_info_by_object_id(config: &AadConfig,
+                                   object_id: &str)
+                                   -> GraphInfoResult<GroupInfo> {
+    let query_url = &format!("{}/groups/{}?$select={}",
+                             config.graph_url(),
+                             url_encode(object_id),
+                             group_properties(config));
     let info_json = get_graph_info(config, query_url)?;
-    let values = &serde_json::from_str::<Value>(&info_json)?["value"];
-    let groups = values
-        .as_array()
-        .ok_or(GraphInfoRetrievalError::BadJSONResponse)?;
+    extract_group_info(config, &serde_json::from_str::<Value>(&info_json)?)
+}
+
+/// Fetch a GroupInfo object for the group with the provided GID, which is either the group's
+/// gidNumber POSIX attribute or mapped by the configured IdMapper
+pub fn get_group_info_by_id(config: &AadConfig, gid: u32) -> GraphInfoResult<GroupInfo> {
+    let mut groups = match config.posix_attributes.group.gid_number {
+        Some(ref attribute) => {
+            find_by_posix_id(config, "groups", &group_properties(config), attribute, gid)?
+                .iter()
+                .filter_map(|v| extract_group_info(config, v).ok())
+                .collect()
+        }
+        None => vec![],
+    };
+    if groups.is_empty() {
+        groups = match config.id_mapper().reverse(ObjectKind::Group, gid)? {
+            Reverse::Equals { property, value } => {
+                find_groups(config, &format!("{}+eq+{}", property, value))?
+            }
+            Reverse::IdAttribute { attribute } => {
+                find_by_posix_id(config, "groups", &group_properties(config), &attribute, gid)?
+                    .iter()
+                    .filter_map(|v| extract_group_info(config, v).ok())
+                    .collect()
+            }
+            Reverse::Scan => {
+                let object_ids = GROUP_INDEX.find(gid, || {
+                    Ok(list_groups(config)?
+                           .into_iter()
+                           .map(|g| (g.group_id, g.object_id))
+                           .collect())
+                })?;
+                let mut groups = vec![];
+                for object_id in object_ids {
+                    match get_group_info_by_object_id(config, &object_id) {
+                        Ok(g) => groups.push(g),
+                        Err(ref e) if e.is_not_found() => {} // deleted since it was listed
+                        Err(e) => return Err(e),
+                    }
+                }
+                groups
+            }
+            Reverse::Unmapped => vec![],
+        };
+    }
+    only_one(groups.into_iter().filter(|g| g.group_id == gid).collect())
+}
+
+/// Return the users that match `filter`, an OData `$filter` expression.
+pub fn find_users(config: &AadConfig, filter: &str) -> GraphInfoResult<Vec<UserInfo>> {
+    let url = format!("{}/users?$filter={}&$select={}",
+                      config.graph_url(),
+                      filter,
+                      user_properties(config));
+    Ok(get_all_pages(config, url)?
+           .iter()
+           .filter_map(|v| extract_user_info(config, v).ok())
+           .collect())
+}
+
+/// Return the groups that match `filter`, an OData `$filter` expression.
+pub fn find_groups(config: &AadConfig, filter: &str) -> GraphInfoResult<Vec<GroupInfo>> {
+    let url = format!("{}/groups?$filter={}&$select={}",
+                      config.graph_url(),
+                      filter,
+                      group_properties(config));
+    Ok(get_all_pages(config, url)?
+           .iter()
+           .filter_map(|v| extract_group_info(config, v).ok())
+           .collect())
+}
+
+/// Return the objects in `collection` (`users` or `groups`) whose POSIX ID `attribute` is `id`,
+/// with the given `properties`.
+///
+/// The attribute may hold IDs as integers or, if it is a string extension attribute, as
+/// strings (see `posix::number`). Graph refuses to compare an attribute with a literal of the
+/// other type, so if it refuses an integer, the ID is asked for again as a string, and the
+/// attribute is asked about with strings from then on.
+fn find_by_posix_id(config: &AadConfig,
+                    collection: &str,
+                    properties: &str,
+                    attribute: &str,
+                    id: u32)
+                    -> GraphInfoResult<Vec<Value>> {
+    use error::GraphInfoRetrievalError::BadHTTPResponse;
+
+    let advanced = posix::needs_advanced_query(attribute);
+    let url = |literal: &str| {
+        format!("{}/{}?$filter={}+eq+{}&$select={}{}",
+                config.graph_url(),
+                collection,
+                attribute,
+                literal,
+                properties,
+                if advanced { "&$count=true" } else { "" })
+    };
+    let is_string = STRING_ID_ATTRIBUTES
+        .lock()
+        .map(|a| a.contains(attribute))
+        .unwrap_or(false);
+    if !is_string {
+        match get_all_pages_with(config, url(&id.to_string()), advanced) {
+            Err(BadHTTPResponse { status: StatusCode::BadRequest, .. }) => {}
+            result => return result,
+        }
+    }
+    let values = get_all_pages_with(config, url(&odata_string(&id.to_string())), advanced)?;
+    if !is_string {
+        if let Ok(mut attributes) = STRING_ID_ATTRIBUTES.lock() {
+            attributes.insert(attribute.to_string());
+        }
+    }
+    Ok(values)
+}
 
-    if groups.len() > 1 {
+/// The one result of a lookup that should find exactly one thing.
+pub fn only_one<T>(mut results: Vec<T>) -> GraphInfoResult<T> {
+    if results.len() > 1 {
         return Err(GraphInfoRetrievalError::TooManyResults);
     }
-    if groups.len() < 1 {
-        return Err(GraphInfoRetrievalError::NotFound);
+    results.pop().ok_or(GraphInfoRetrievalError::NotFound)
+}
+
+/// The direct members of a group
+#[derive(Clone)]
+pub struct GroupMembers {
+    pub users: Vec<UserInfo>,
+    /// The object IDs of groups nested in this one
+    pub groups: Vec<String>,
+}
+
+/// Return the direct members of the group identified by the supplied group's object ID, from
+/// every page of its `members` collection.
+pub fn get_group_members(config: &AadConfig, object_id: &str) -> GraphInfoResult<GroupMembers> {
+    let url = format!("{}/groups/{}/members?$select={}",
+                      config.graph_url(),
+                      object_id,
+                      user_properties(config));
+    Ok(extract_group_members(config, &get_all_pages(config, url)?))
+}
+
+/// The groups to which a user belongs
+pub struct Memberships {
+    /// The object IDs of all of the groups, including those that cannot be given a GID
+    pub object_ids: Vec<String>,
+    /// The groups that can be given a GID
+    pub groups: Vec<GroupInfo>,
+}
+
+/// Return the groups to which the named user belongs.
+///
+/// `memberOf` also lists directory roles and administrative units; the cast to
+/// `microsoft.graph.group` leaves only groups in the results.
+///
+/// If `transitive_groups` is set, groups the user belongs to through membership of other groups
+/// are included too.
+pub fn get_user_memberships(config: &AadConfig, user: &str) -> GraphInfoResult<Memberships> {
+    let values = get_memberships(config, user)?;
+    Ok(Memberships {
+           object_ids: values
+               .iter()
+               .filter_map(|v| v["id"].as_str().map(|id| id.to_string()))
+               .collect(),
+           groups: values
+               .iter()
+               .filter_map(|v| extract_group_info(config, v).ok())
+               .collect(),
+       })
+}
+
+/// Fetch the raw Graph objects for the groups to which the named user belongs.
+fn get_memberships(config: &AadConfig, user: &str) -> GraphInfoResult<Vec<Value>> {
+    let membership = if config.transitive_groups {
+        "transitiveMemberOf"
+    } else {
+        "memberOf"
+    };
+    let url = format!("{}/users/{}/{}/microsoft.graph.group?$select={}",
+                      config.graph_url(),
+                      url_encode(user),
+                      membership,
+                      group_properties(config));
+    #[cfg(debug_assertions)]
+    println!("libnss-aad::azure getting groups for {}", user);
+    match get_all_pages(config, url) {
+        Ok(v) => Ok(v),
+        Err(GraphInfoRetrievalError::BadHTTPResponse { status: StatusCode::NotFound, .. }) => {
+            Ok(vec![])
+        }
+        Err(e) => Err(e),
     }
-    extract_group_info(&groups[0])
 }
 
-/// Return a vector of UserInfo objects representing the members of the group identified by the
-/// supplied group's object ID
-pub fn get_group_members(config: &AadConfig, object_id: &str) -> GraphInfoResult<Vec<UserInfo>> {
-    let group_members_json = get_graph_info(config,
-                                            &format!("https://graph.windows.net/{}/groups/{}/members?api-version=1.6",
-                                                     config.tenant,
-                                                     object_id))?;
-    extract_group_members(&group_members_json)
+/// Return a vector of UserInfo objects representing every user in the directory that can be
+/// represented as a passwd entry.
+pub fn list_users(config: &AadConfig) -> GraphInfoResult<Vec<UserInfo>> {
+    let url = format!("{}/users?$select={}&$top=999",
+                      config.graph_url(),
+                      user_properties(config));
+    Ok(get_all_pages(config, url)?
+           .iter()
+           .filter_map(|v| extract_user_info(config, v).ok())
+           .collect())
+}
+
+/// A group, as listed by list_groups_with_members
+pub struct ListedGroup {
+    pub object_id: String,
+    /// The group, if it can be given a GID
+    pub info: Option<GroupInfo>,
+    pub members: GroupMembers,
 }
 
-/// Return a vector of GroupInfo objects representing the groups to which the named user belongs
-pub fn get_user_groups(config: &AadConfig, username: &str) -> GraphInfoResult<Vec<GroupInfo>> {
-    let mut url = format!("https://graph.windows.net/{}/users/{}/memberOf?api-version=1.6",
-                          config.tenant,
-                          username);
-    let mut user_groups = vec![];
+/// Return every group in the directory, including those that cannot be given a GID, with its
+/// direct members.
+///
+/// Members are expanded in the listing itself, so that only the groups with too many members
+/// for that cost a request of their own.
+pub fn list_groups_with_members(config: &AadConfig) -> GraphInfoResult<Vec<ListedGroup>> {
+    let url = format!("{}/groups?$select={}&$expand=members($select={})&$top=999",
+                      config.graph_url(),
+                      group_properties(config),
+                      user_properties(config));
+    let mut groups = vec![];
+    for v in get_all_pages(config, url)? {
+        let object_id = match v["id"].as_str() {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        let expanded = v["members"].as_array().map_or(&[][..], |m| &m[..]);
+        let members = if expanded.len() >= EXPANDED_MEMBERS_LIMIT {
+            get_group_members(config, &object_id)?
+        } else {
+            extract_group_members(config, expanded)
+        };
+        groups.push(ListedGroup {
+                        info: extract_group_info(config, &v).ok(),
+                        object_id,
+                        members,
+                    });
+    }
+    Ok(groups)
+}
+
+/// Return a vector of GroupInfo objects representing every group in the directory that can be
+/// represented as a group entry.
+pub fn list_groups(config: &AadConfig) -> GraphInfoResult<Vec<GroupInfo>> {
+    let url = format!("{}/groups?$select={}&$top=999",
+                      config.graph_url(),
+                      group_properties(config));
+    Ok(get_all_pages(config, url)?
+           .iter()
+           .filter_map(|v| extract_group_info(config, v).ok())
+           .collect())
+}
+
+/// Fetch every page of the collection at `url`, and return the objects from all of them.
+///
+/// Graph returns large collections a page at a time, with a link to the next page in each.
+fn get_all_pages(config: &AadConfig, url: String) -> GraphInfoResult<Vec<Value>> {
+    get_all_pages_with(config, url, false)
+}
+
+/// Fetch every page of a collection, as `get_all_pages`, as an advanced query if `advanced`
+/// (see `get_graph_info_with`).
+fn get_all_pages_with(config: &AadConfig,
+                      url: String,
+                      advanced: bool)
+                      -> GraphInfoResult<Vec<Value>> {
+    let mut url = url;
+    let mut values = vec![];
     let mut retries = 5;
     loop {
-        #[cfg(debug_assertions)]
-        println!("libnss-aad::azure getting a batch of groups for {}",
-                 username);
-        let user_groups_json = match get_graph_info(config, &url) {
+        let page_json = match get_graph_info_with(config, &url, advanced) {
             Ok(j) => j,
-            Err(e) => {
-                match e {
-                    GraphInfoRetrievalError::BadHTTPResponse { status, data } => {
-                        if status == hyper::status::StatusCode::NotFound {
-                            return Ok(vec![]);
-                        }
-                        if data.contains("Directory_ExpiredPageToken") && retries > 0 {
-                            #[cfg(debug_assertions)]
-                            println!("libnss-aad::azure got an ExpiredPageToken; retrying");
-                            retries -= 1;
-                            continue; // no kidding, this is the recommended approach.
-                        }
-                        return Err(GraphInfoRetrievalError::BadHTTPResponse { status, data });
-                    }
-                    _ => {
-                        return Err(e);
-                    }
+            Err(GraphInfoRetrievalError::BadHTTPResponse { status, data }) => {
+                if data.contains("Directory_ExpiredPageToken") && retries > 0 {
+                    #[cfg(debug_assertions)]
+                    println!("libnss-aad::azure got an ExpiredPageToken; retrying");
+                    retries -= 1;
+                    continue; // no kidding, this is the recommended approach.
                 }
+                return Err(GraphInfoRetrievalError::BadHTTPResponse { status, data });
+            }
+            Err(e) => {
+                return Err(e);
             }
         };
-        let mut group_batch = extract_user_groups(&user_groups_json)?;
-        user_groups.append(&mut group_batch);
-        let link = match has_another_page(&user_groups_json)? {
+        let page = serde_json::from_str::<Value>(&page_json)?;
+        let mut batch = page["value"]
+            .as_array()
+            .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
+            .clone();
+        values.append(&mut batch);
+        url = match has_another_page(&page_json)? {
             Some(link) => link,
             None => {
                 break;
             }
         };
-        url = format!("https://graph.windows.net/{}/{}&api-version=1.6",
-                      config.tenant,
-                      link);
     }
-    Ok(user_groups)
+    Ok(values)
 }
 
 /// Fetch the text of the HTTP response at `query_url`
 ///
-/// Using the client credentials in the `config` argument, obtain an OAuth2 Bearer token from
-/// the OAuth2 endpoint. Using that token, make a request for `query_url`, and return whatever
+/// Using the credentials in the `config` argument, obtain an OAuth2 Bearer token (see
+/// `auth::get_access_token`). Using that token, make a request for `query_url`, and return whatever
 /// text is in the response body.
 fn get_graph_info(config: &AadConfig, query_url: &str) -> GraphInfoResult<String> {
-    let auth_url = format!("https://login.microsoftonline.com/{}/oauth2/token?api-version=1.0",
-                           config.tenant);
-    let auth_params = vec![("resource", "https://graph.windows.net/"),
-                           ("grant_type", "client_credentials"),
-                           ("client_id", &config.client_id),
-                           ("client_secret", &config.client_secret)];
-    let token_json = post_query(&auth_url, &auth_params)?;
+    get_graph_info_with(config, query_url, false)
+}
 
-    let token = extract_token(&token_json)?;
+/// Fetch the text of the HTTP response at `query_url`, as `get_graph_info`.
+///
+/// If `advanced`, the request asks for eventual consistency, which Graph requires of advanced
+/// queries, such as filters on custom security attributes. `query_url` must then include
+/// `$count=true`.
+fn get_graph_info_with(config: &AadConfig,
+                       query_url: &str,
+                       advanced: bool)
+                       -> GraphInfoResult<String> {
+    let token = auth::get_access_token(config)?;
 
     let mut auth_header = Headers::new();
-    auth_header.set(Authorization(Bearer { token: token }));
+    auth_header.set(Authorization(Bearer { token }));
+    if advanced {
+        auth_header.set_raw("ConsistencyLevel", vec![b"eventual".to_vec()]);
+    }
 
     get_content(query_url, Some(auth_header))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{days_since_epoch, odata_string, url_encode};
+
+    #[test]
+    fn days_since_epoch_counts_from_1970() {
+        assert_eq!(days_since_epoch("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(days_since_epoch("1970-01-02T23:59:59Z"), Some(1));
+        assert_eq!(days_since_epoch("2017-04-01T12:00:00Z"), Some(17257));
+    }
+
+    #[test]
+    fn days_since_epoch_counts_leap_days() {
+        assert_eq!(days_since_epoch("2000-02-29T00:00:00Z"), Some(11016));
+        assert_eq!(days_since_epoch("2000-03-01T00:00:00Z"), Some(11017));
+        assert_eq!(days_since_epoch("2100-03-01T00:00:00Z"), Some(47541));
+    }
+
+    #[test]
+    fn days_since_epoch_rejects_malformed_dates() {
+        assert_eq!(days_since_epoch(""), None);
+        assert_eq!(days_since_epoch("2017-04"), None);
+        assert_eq!(days_since_epoch("2017-13-01T00:00:00Z"), None);
+        assert_eq!(days_since_epoch("2017-04-00T00:00:00Z"), None);
+        assert_eq!(days_since_epoch("yesterday, or so"), None);
+    }
+
+    #[test]
+    fn encodes_names_for_urls() {
+        assert_eq!(url_encode("alice@contoso.com"), "alice@contoso.com");
+        assert_eq!(url_encode("a b/c?d#e&f"), "a%20b%2Fc%3Fd%23e%26f");
+        assert_eq!(url_encode("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn quotes_odata_strings() {
+        assert_eq!(odata_string("alice"), "%27alice%27");
+        assert_eq!(odata_string("o'brien"), "%27o%27%27brien%27");
+    }
+}
@@ -0,0 +1,19 @@
+//! Entry point for nss-aad-daemon, which answers the NSS plugin's lookups on a Unix socket.
+//!
+//! Usage: nss-aad-daemon [config-file]
+
+extern crate nss_aad;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+const DEFAULT_CONFIG_FILE: &str = "/etc/nssaad.conf";
+
+fn main() {
+    let config_file = env::args().nth(1).unwrap_or(DEFAULT_CONFIG_FILE.to_string());
+    if let Err(e) = nss_aad::daemon::run(&config_file) {
+        let _ = writeln!(io::stderr(), "nss-aad-daemon: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,48 @@
+//! The plugin's side of the conversation with nss-aad-daemon
+
+use protocol::{self, Request, Response};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// How long to wait for the daemon to answer. Lookups that page through large result sets can
+/// take a while, but a wedged daemon shouldn't hang every process on the host forever.
+const RESPONSE_TIMEOUT_SECS: u64 = 30;
+
+/// How long to wait for an enumeration, which lists every user or group in the directory when
+/// the daemon has no recent listing.
+const ENUMERATION_TIMEOUT_SECS: u64 = 600;
+
+/// Send `request` to the daemon, and return its response.
+///
+/// Failing to reach the daemon at all yields `Response::Unavailable`; the daemon failing to
+/// answer in time (or answering gibberish) yields `Response::TryAgain`.
+pub fn query(request: &Request) -> Response {
+    let stream = match UnixStream::connect(protocol::SOCKET_PATH) {
+        Ok(s) => s,
+        Err(_) => {
+            #[cfg(debug_assertions)]
+            println!("libnss-aad could not connect to {}", protocol::SOCKET_PATH);
+            return Response::Unavailable;
+        }
+    };
+    let timeout = match *request {
+        Request::EnumeratePasswd | Request::EnumerateGroup => ENUMERATION_TIMEOUT_SECS,
+        _ => RESPONSE_TIMEOUT_SECS,
+    };
+    let timeout = Some(Duration::from_secs(timeout));
+    if stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)).is_err() {
+        return Response::TryAgain;
+    }
+
+    #[allow(unused_variables)]
+    // Responses (enumerations, in particular) may be as large as the directory.
+    match protocol::write_message(&stream, request)
+              .and_then(|_| protocol::read_message(&stream, u64::MAX)) {
+        Ok(response) => response,
+        Err(e) => {
+            #[cfg(debug_assertions)]
+            println!("libnss-aad got no response to {:?}: {:?}", request, e);
+            Response::TryAgain
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! National clouds, and the endpoints through which each is reached
+//!
+//! Azure US Government and Azure China are separate instances of AAD and Microsoft Graph, with
+//! their own hostnames. The configuration names one of them with `cloud`, or names the
+//! endpoints explicitly with `authority` and `graph_endpoint` (which is also how the plugin is
+//! pointed at a mock Graph server).
+
+use AadConfig;
+
+#[derive(Deserialize,Debug,Clone,Copy,Default)]
+pub enum Cloud {
+    #[serde(rename = "public")]
+    #[default]
+    Public,
+    #[serde(rename = "usgov")]
+    UsGovernment,
+    #[serde(rename = "china")]
+    China,
+}
+
+impl Cloud {
+    /// The base URL of the cloud's OAuth2 authority.
+    fn authority(&self) -> &'static str {
+        match *self {
+            Cloud::Public => "https://login.microsoftonline.com",
+            Cloud::UsGovernment => "https://login.microsoftonline.us",
+            Cloud::China => "https://login.chinacloudapi.cn",
+        }
+    }
+
+    /// The base URL of the cloud's Microsoft Graph service.
+    fn graph_endpoint(&self) -> &'static str {
+        match *self {
+            Cloud::Public => "https://graph.microsoft.com",
+            Cloud::UsGovernment => "https://graph.microsoft.us",
+            Cloud::China => "https://microsoftgraph.chinacloudapi.cn",
+        }
+    }
+}
+
+impl AadConfig {
+    /// The base URL of the OAuth2 authority, without a trailing slash.
+    pub fn authority(&self) -> &str {
+        match self.authority {
+            Some(ref authority) => authority.trim_end_matches('/'),
+            None => self.cloud.authority(),
+        }
+    }
+
+    /// The base URL of Microsoft Graph (which doubles as its OAuth2 resource identifier),
+    /// without a trailing slash.
+    pub fn graph_endpoint(&self) -> &str {
+        match self.graph_endpoint {
+            Some(ref endpoint) => endpoint.trim_end_matches('/'),
+            None => self.cloud.graph_endpoint(),
+        }
+    }
+
+    /// The base URL of the Microsoft Graph v1.0 API, to which request paths are appended.
+    pub fn graph_url(&self) -> String {
+        format!("{}/v1.0", self.graph_endpoint())
+    }
+}
@@ -0,0 +1,159 @@
+//! nss-aad-daemon: answers the plugin's lookups on a Unix socket
+//!
+//! The daemon runs as a dedicated user, which is the only user that needs to be able to read the
+//! configuration file and the credentials it names. Every other process on the host asks the
+//! daemon over its socket, so they never see the credentials themselves.
+
+use AadConfig;
+use libc;
+use lookup::Directory;
+use protocol::{self, Request, Response};
+use std::fs;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a client gets to send its whole request before the daemon gives up on it.
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+/// How long a client gets to read the response.
+const RESPONSE_TIMEOUT_SECS: u64 = 10;
+
+/// How many requests are answered at once. Further connections wait (in the listen backlog)
+/// until a worker is free, so that no number of clients can exhaust the daemon's threads.
+const WORKERS: usize = 16;
+
+/// Serve lookups on the plugin's socket, using the configuration in `config_file`. This only
+/// returns if the daemon cannot start.
+pub fn run(config_file: &str) -> io::Result<()> {
+    let directory = match AadConfig::from_file(config_file) {
+        Ok(c) => Arc::new(Directory::new(c)),
+        Err(e) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{}: {}", config_file, e)))
+        }
+    };
+
+    let socket_path = Path::new(protocol::SOCKET_PATH);
+    if socket_path.exists() {
+        fs::remove_file(socket_path)?; // left behind by a previous instance
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    // Anybody may ask; what they may ask is limited by the protocol.
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o666))?;
+
+    let (sender, receiver) = mpsc::sync_channel::<UnixStream>(0);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let directory = directory.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || work(&directory, &receiver));
+    }
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        if sender.send(stream).is_err() {
+            break; // every worker has gone
+        }
+    }
+    Ok(())
+}
+
+/// Answer requests from the connections handed over by `run`, one at a time, for as long as
+/// the daemon runs.
+fn work(directory: &Directory, receiver: &Mutex<mpsc::Receiver<UnixStream>>) {
+    loop {
+        let stream = match receiver.lock() {
+            Ok(r) => r.recv(),
+            Err(_) => return,
+        };
+        match stream {
+            // A panic while answering one request must not cost the daemon a worker.
+            Ok(stream) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| serve(directory, stream)));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// A stream that must be read from completely before a deadline, however slowly the other end
+/// sends, rather than within a timeout for each read.
+struct Deadline<'a> {
+    stream: &'a UnixStream,
+    deadline: Instant,
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+        }
+        self.stream.set_read_timeout(Some(self.deadline - now))?;
+        self.stream.read(buf)
+    }
+}
+
+/// The UID of the process at the other end of `stream`, as it was when it connected.
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(stream.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void,
+                         &mut len)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Answer the single request that arrives on `stream`.
+#[allow(unused_variables)]
+fn serve(directory: &Directory, stream: UnixStream) {
+    if stream.set_write_timeout(Some(Duration::from_secs(RESPONSE_TIMEOUT_SECS))).is_err() {
+        return;
+    }
+    let request = Deadline {
+        stream: &stream,
+        deadline: Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS),
+    };
+    let response = match protocol::read_message::<Request, _>(request,
+                                                            protocol::MAX_REQUEST_LEN) {
+        // Like /etc/shadow, shadow entries are only for root. Anybody else is told that the
+        // shadow database is unavailable, as they would be by `files`.
+        Ok(Request::GetSpNam(_)) if peer_uid(&stream).ok() != Some(0) => Response::Unavailable,
+        Ok(request) => {
+            #[cfg(debug_assertions)]
+            println!("nss-aad-daemon handling {:?}", request);
+            directory.handle(&request)
+        }
+        Err(e) => {
+            #[cfg(debug_assertions)]
+            println!("nss-aad-daemon got a bad request: {:?}", e);
+            Response::Unavailable
+        }
+    };
+    if let Err(e) = protocol::write_message(&stream, &response) {
+        #[cfg(debug_assertions)]
+        println!("nss-aad-daemon could not respond: {:?}", e);
+    }
+}
@@ -1,5 +1,6 @@
 
 extern crate hyper;
+extern crate openssl;
 extern crate serde_json;
 extern crate url;
 
@@ -34,6 +35,36 @@ pub enum GraphInfoRetrievalError {
     UnusableImmutableID,
     TooManyResults,
     NotFound,
+    NoClientCredentials,
+    BadClientCertificate,
+    BadFederatedToken,
+    /// A file kept on this host, such as the ID allocation file, could not be read or written
+    LocalStorage(std::io::Error),
+}
+
+impl GraphInfoRetrievalError {
+    /// Whether the error suggests that AAD (or the network between us and it) is having
+    /// trouble, as opposed to having given a definite answer.
+    pub fn is_outage(&self) -> bool {
+        match *self {
+            GraphInfoRetrievalError::HTTPError(_) => true,
+            GraphInfoRetrievalError::BadHTTPResponse { ref status, .. } => {
+                status.is_server_error()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the error means that there was nothing to find.
+    pub fn is_not_found(&self) -> bool {
+        match *self {
+            GraphInfoRetrievalError::BadHTTPResponse { ref status, .. } => {
+                *status == hyper::status::StatusCode::NotFound
+            }
+            GraphInfoRetrievalError::NotFound => true,
+            _ => false,
+        }
+    }
 }
 
 impl From<serde_json::Error> for GraphInfoRetrievalError {
@@ -54,6 +85,12 @@ impl From<std::io::Error> for GraphInfoRetrievalError {
     }
 }
 
+impl From<openssl::error::ErrorStack> for GraphInfoRetrievalError {
+    fn from(_: openssl::error::ErrorStack) -> GraphInfoRetrievalError {
+        GraphInfoRetrievalError::BadClientCertificate
+    }
+}
+
 impl From<std::num::ParseIntError> for GraphInfoRetrievalError {
     fn from(_: std::num::ParseIntError) -> GraphInfoRetrievalError {
         GraphInfoRetrievalError::UnusableImmutableID
@@ -0,0 +1,120 @@
+//! Helpers for the small files that the plugin persists between processes
+//!
+//! Anything that is read back from disk may have been written by another process, so these
+//! helpers are careful about who owns a file before trusting its contents, and about never
+//! leaving a half-written file in place.
+
+use libc;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+
+/// Whether a file with the given metadata may be trusted by this process.
+///
+/// The file must be a regular file owned by root or by the effective user, and must not be
+/// writable by group or other.
+pub fn is_trusted(metadata: &Metadata) -> bool {
+    let euid = unsafe { libc::geteuid() };
+    metadata.is_file() && (metadata.uid() == 0 || metadata.uid() == euid) &&
+    metadata.mode() & 0o022 == 0
+}
+
+/// Open `path` for reading, but only if it passes the `is_trusted` checks.
+pub fn open_trusted(path: &Path) -> io::Result<File> {
+    let file = File::open(path)?;
+    if !is_trusted(&file.metadata()?) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{} has unsafe ownership or mode", path.display())));
+    }
+    Ok(file)
+}
+
+/// Replace the contents of `path` with `contents`, such that readers see either the old or the
+/// new contents but never a mix of the two.
+///
+/// The data is written to a temporary file in the same directory, flushed to disk, and then
+/// renamed over `path`. The directory is flushed too, so that the rename survives a crash.
+///
+/// Writers of the same `path` must be serialised. A temporary file left behind by a crash is
+/// removed first, since a restarted daemon may well have the same PID (as PID 1 in a
+/// container, say).
+pub fn write_atomically(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let tmp_path = path.with_extension(format!("tmp.{}", process::id()));
+    match fs::remove_file(&tmp_path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        result => result?,
+    }
+    let result = {
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp_path)?;
+        tmp.write_all(contents)
+            .and_then(|_| tmp.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, path))
+    };
+    let result = result.and_then(|_| match path.parent() {
+        Some(dir) if dir != Path::new("") => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Take an exclusive lock on `path` (creating it if need be). The lock is held until the
+/// returned File is dropped.
+pub fn lock_exclusive(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+    use super::{open_trusted, write_atomically};
+
+    #[test]
+    fn replaces_a_temporary_file_left_by_a_crash() {
+        let path = env::temp_dir().join(format!("nss-aad-fsutil-test.{}", process::id()));
+        let tmp_path = path.with_extension(format!("tmp.{}", process::id()));
+        fs::write(&tmp_path, b"half-written").unwrap();
+
+        write_atomically(&path, b"contents", 0o600).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"contents");
+        assert!(!tmp_path.exists());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn only_trusts_files_that_others_cannot_write() {
+        let path = env::temp_dir().join(format!("nss-aad-fsutil-trust-test.{}", process::id()));
+        fs::write(&path, b"contents").unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(open_trusted(&path).is_ok());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o664)).unwrap();
+        assert!(open_trusted(&path).is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o646)).unwrap();
+        assert!(open_trusted(&path).is_err());
+        assert!(open_trusted(&env::temp_dir()).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
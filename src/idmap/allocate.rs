@@ -0,0 +1,332 @@
+//! IDs handed out from a configured range, in the order that objects are first seen, and
+//! remembered in a file, as with winbind's idmap_tdb.
+//!
+//! Unlike a hash, an allocated ID never collides with another, and never changes once it has
+//! been handed out. An ID is written to the file before it is used, so it survives crashes and
+//! restarts, and finding the object for an ID is a matter of reading the file. The file is the
+//! only record of which object owns which ID, so it must be kept (and backed up) as carefully
+//! as any other file whose loss would leave files on disk with the wrong owners.
+//!
+//! IDs are only handed out to objects found by lookups that somebody asked for by name (see
+//! `allocating`), such as `getpwnam` or a login, and not to every object that happens to be
+//! listed by enumeration or among a group's members; those without an ID are left out. The IDs
+//! handed out during one lookup are written to the file together, at the end of it.
+
+extern crate serde_json;
+
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use fsutil;
+use self::serde_json::Value;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use super::{IdMapper, ObjectKind, Reverse};
+
+lazy_static! {
+    /// The allocations in each file, loaded when first needed, keyed by the file's path
+    static ref ALLOCATIONS: Mutex<HashMap<String, Allocations>> = Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    /// Whether the lookup running on this thread may hand out new IDs
+    static ALLOCATING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The contents of an allocation file. Users and groups share one range, so that no user
+/// has the same number as any group.
+#[derive(Serialize,Deserialize,Debug,Default)]
+struct Allocations {
+    /// Keyed by (lower-case) object ID
+    ids: HashMap<String, u32>,
+    /// IDs that have been handed out but not yet written to the file. Only lookups that may
+    /// allocate IDs use them, and only once they have been written.
+    #[serde(skip_serializing, skip_deserializing)]
+    pending: HashMap<String, u32>,
+}
+
+impl Allocations {
+    /// Read the allocations in `path`. A missing file means that nothing has been allocated
+    /// yet, but a file that can't be read or trusted is an error: starting afresh would hand
+    /// out IDs that already belong to somebody.
+    fn load(path: &Path) -> io::Result<Allocations> {
+        let mut contents = String::new();
+        match fsutil::open_trusted(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => {
+                serde_json::from_str(&contents)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Allocations::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the pending IDs to `path`, along with the rest. If that fails, they stay pending.
+    fn commit(&mut self, path: &Path) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut ids = self.ids.clone();
+        ids.extend(self.pending.iter().map(|(k, &v)| (k.clone(), v)));
+        let contents = serde_json::to_vec(&Allocations {
+                                               ids,
+                                               pending: HashMap::new(),
+                                           })
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fsutil::write_atomically(path, &contents, 0o600)?;
+        let pending = self.pending.drain().collect::<Vec<_>>();
+        self.ids.extend(pending);
+        Ok(())
+    }
+}
+
+/// Resets ALLOCATING when an `allocating` lookup ends, even if it panics.
+struct AllocatingGuard {
+    was: bool,
+}
+
+impl Drop for AllocatingGuard {
+    fn drop(&mut self) {
+        let was = self.was;
+        ALLOCATING.with(|a| a.set(was));
+    }
+}
+
+/// The error for a lookup that finds ALLOCATIONS poisoned by a panic while it was held, which
+/// may have left the allocations half-updated.
+fn poisoned() -> GraphInfoRetrievalError {
+    GraphInfoRetrievalError::LocalStorage(io::Error::other("the ID allocations lock is poisoned"))
+}
+
+/// Run `f`, letting it hand out IDs from `file` to the objects it finds, and write those IDs
+/// to the file before returning what it found.
+pub fn allocating<T, F>(file: &str, f: F) -> GraphInfoResult<T>
+    where F: FnOnce() -> GraphInfoResult<T>
+{
+    let found = {
+        let _guard = AllocatingGuard { was: ALLOCATING.with(|a| a.replace(true)) };
+        f()?
+    };
+    let mut all = ALLOCATIONS
+        .lock()
+        .map_err(|_| poisoned())?;
+    if let Some(allocations) = all.get_mut(file) {
+        // Nothing that was given an ID may be used until the ID is on disk
+        allocations
+            .commit(Path::new(file))
+            .map_err(GraphInfoRetrievalError::LocalStorage)?;
+    }
+    Ok(found)
+}
+
+pub struct Allocate<'a> {
+    file: &'a str,
+    min: u32,
+    max: u32,
+}
+
+impl<'a> Allocate<'a> {
+    pub fn new(file: &'a str, min: u32, max: u32) -> Allocate<'a> {
+        Allocate {
+            file,
+            min,
+            max,
+        }
+    }
+
+    /// Run `f` on this mapper's allocations, loading them from the file if need be.
+    fn with_allocations<T, F>(&self, f: F) -> GraphInfoResult<T>
+        where F: FnOnce(&mut Allocations) -> GraphInfoResult<T>
+    {
+        let mut all = ALLOCATIONS
+            .lock()
+            .map_err(|_| poisoned())?;
+        if !all.contains_key(self.file) {
+            let allocations = Allocations::load(Path::new(self.file))
+                .map_err(GraphInfoRetrievalError::LocalStorage)?;
+            all.insert(self.file.to_string(), allocations);
+        }
+        f(all.get_mut(self.file).unwrap())
+    }
+}
+
+impl<'a> IdMapper for Allocate<'a> {
+    fn property(&self, _kind: ObjectKind) -> &str {
+        "id"
+    }
+
+    fn id_for(&self, _kind: ObjectKind, object: &Value) -> GraphInfoResult<u32> {
+        let object_id = object["id"]
+            .as_str()
+            .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
+            .to_lowercase();
+        self.with_allocations(|allocations| {
+            if let Some(&id) = allocations.ids.get(&object_id) {
+                return Ok(id);
+            }
+            if !ALLOCATING.with(|a| a.get()) {
+                // Not given an ID yet, so not a user or group on this host yet either
+                return Err(GraphInfoRetrievalError::NotFound);
+            }
+            if let Some(&id) = allocations.pending.get(&object_id) {
+                return Ok(id);
+            }
+            let next = allocations
+                .ids
+                .values()
+                .chain(allocations.pending.values())
+                .max()
+                .and_then(|id| id.checked_add(1))
+                .map_or(self.min, |id| id.max(self.min));
+            if next > self.max {
+                return Err(GraphInfoRetrievalError::UnusableImmutableID);
+            }
+            #[cfg(debug_assertions)]
+            println!("libnss-aad allocating ID {} to {}", next, object_id);
+            allocations.pending.insert(object_id, next);
+            Ok(next)
+        })
+    }
+
+    fn reverse(&self, _kind: ObjectKind, id: u32) -> GraphInfoResult<Reverse> {
+        self.with_allocations(|allocations| {
+            Ok(match allocations.ids.iter().find(|&(_, &v)| v == id) {
+                   Some((object_id, _)) => {
+                       Reverse::Equals {
+                           property: "id".to_string(),
+                           value: format!("'{}'", object_id),
+                       }
+                   }
+                   None => Reverse::Unmapped,
+               })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::GraphInfoRetrievalError;
+    use idmap::{IdMapper, ObjectKind};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use super::{ALLOCATIONS, Allocate, Allocations, allocating, serde_json};
+
+    /// A file of allocations for one test, which no other test uses.
+    fn file(test: &str) -> String {
+        let path = env::temp_dir()
+            .join(format!("nss-aad-allocate-test.{}.{}", process::id(), test));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn object(object_id: &str) -> serde_json::Value {
+        serde_json::from_str(&format!("{{\"id\": \"{}\"}}", object_id)).unwrap()
+    }
+
+    fn id_for(mapper: &Allocate, object_id: &str) -> Result<u32, GraphInfoRetrievalError> {
+        mapper.id_for(ObjectKind::User, &object(object_id))
+    }
+
+    /// Forget what has been loaded from `file`, as if the daemon had restarted.
+    fn restart(file: &str) {
+        ALLOCATIONS.lock().unwrap().remove(file);
+    }
+
+    #[test]
+    fn commit_writes_pending_ids() {
+        let path = PathBuf::from(file("commit"));
+        let mut allocations = Allocations::default();
+        allocations.ids.insert("a".to_string(), 1000);
+        allocations.pending.insert("b".to_string(), 1001);
+
+        allocations.commit(&path).unwrap();
+        assert!(allocations.pending.is_empty());
+        assert_eq!(allocations.ids.get("b"), Some(&1001));
+        let loaded = Allocations::load(&path).unwrap();
+        assert_eq!(loaded.ids, allocations.ids);
+        assert!(loaded.pending.is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn failed_commit_leaves_ids_pending() {
+        let path = env::temp_dir().join("nss-aad-no-such-directory").join("ids.json");
+        let mut allocations = Allocations::default();
+        allocations.pending.insert("b".to_string(), 1001);
+
+        assert!(allocations.commit(&path).is_err());
+        assert_eq!(allocations.pending.get("b"), Some(&1001));
+        assert!(allocations.ids.is_empty());
+    }
+
+    #[test]
+    fn ids_are_only_handed_out_while_allocating() {
+        let file = file("allocating");
+        let mapper = Allocate::new(&file, 5000, 5999);
+        assert!(matches!(id_for(&mapper, "A"), Err(GraphInfoRetrievalError::NotFound)));
+
+        assert_eq!(allocating(&file, || id_for(&mapper, "A")).unwrap(), 5000);
+        assert_eq!(allocating(&file, || id_for(&mapper, "b")).unwrap(), 5001);
+        // Object IDs are not case-sensitive, and an ID once handed out is kept
+        assert_eq!(id_for(&mapper, "a").unwrap(), 5000);
+        assert_eq!(allocating(&file, || id_for(&mapper, "B")).unwrap(), 5001);
+        let _ = fs::remove_file(&file);
+    }
+
+    #[test]
+    fn ids_survive_a_restart() {
+        let file = file("restart");
+        let mapper = Allocate::new(&file, 5000, 5999);
+        assert_eq!(allocating(&file, || id_for(&mapper, "a")).unwrap(), 5000);
+
+        restart(&file);
+        assert_eq!(id_for(&mapper, "a").unwrap(), 5000);
+        assert_eq!(allocating(&file, || id_for(&mapper, "b")).unwrap(), 5001);
+        let _ = fs::remove_file(&file);
+    }
+
+    #[test]
+    fn ids_from_a_failed_lookup_are_not_used_until_written() {
+        let file = file("failed");
+        let mapper = Allocate::new(&file, 5000, 5999);
+        let failed: Result<u32, _> = allocating(&file, || {
+            id_for(&mapper, "a")?;
+            Err(GraphInfoRetrievalError::NotFound)
+        });
+        assert!(failed.is_err());
+        assert!(matches!(id_for(&mapper, "a"), Err(GraphInfoRetrievalError::NotFound)));
+
+        // The next lookup to allocate writes them, and does not hand the same ID out again
+        assert_eq!(allocating(&file, || id_for(&mapper, "b")).unwrap(), 5001);
+        assert_eq!(id_for(&mapper, "a").unwrap(), 5000);
+        restart(&file);
+        assert_eq!(id_for(&mapper, "a").unwrap(), 5000);
+        let _ = fs::remove_file(&file);
+    }
+
+    #[test]
+    fn exhausted_range_hands_out_nothing() {
+        let file = file("exhausted");
+        let mapper = Allocate::new(&file, 5000, 5000);
+        assert_eq!(allocating(&file, || id_for(&mapper, "a")).unwrap(), 5000);
+        assert!(matches!(allocating(&file, || id_for(&mapper, "b")),
+                         Err(GraphInfoRetrievalError::UnusableImmutableID)));
+        let _ = fs::remove_file(&file);
+    }
+
+    #[test]
+    fn unreadable_file_hands_out_nothing() {
+        let file = file("unreadable");
+        fs::write(&file, b"not json").unwrap();
+        let mapper = Allocate::new(&file, 5000, 5999);
+        match allocating(&file, || id_for(&mapper, "a")) {
+            Err(e @ GraphInfoRetrievalError::LocalStorage(_)) => assert!(!e.is_outage()),
+            result => panic!("expected a local storage error, not {:?}", result),
+        }
+        assert_eq!(fs::read(&file).unwrap(), b"not json");
+        let _ = fs::remove_file(&file);
+    }
+}
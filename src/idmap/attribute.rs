@@ -0,0 +1,78 @@
+//! IDs read from a numeric directory attribute, such as a schema extension holding a uidNumber
+//! or gidNumber.
+//!
+//! The attribute is read and searched just as the `uid_number` and `gid_number` POSIX attributes
+//! are (see `posix`), so it may hold IDs as integers or as strings.
+
+extern crate serde_json;
+
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use posix;
+use self::serde_json::Value;
+use super::{IdMapper, ObjectKind, Reverse};
+
+pub struct Attribute<'a> {
+    user_attribute: &'a str,
+    group_attribute: &'a str,
+}
+
+impl<'a> Attribute<'a> {
+    pub fn new(user_attribute: &'a str, group_attribute: &'a str) -> Attribute<'a> {
+        Attribute {
+            user_attribute,
+            group_attribute,
+        }
+    }
+}
+
+impl<'a> IdMapper for Attribute<'a> {
+    fn property(&self, kind: ObjectKind) -> &str {
+        match kind {
+            ObjectKind::User => self.user_attribute,
+            ObjectKind::Group => self.group_attribute,
+        }
+    }
+
+    fn id_for(&self, kind: ObjectKind, object: &Value) -> GraphInfoResult<u32> {
+        posix::number_at(object, self.property(kind))?
+            .ok_or(GraphInfoRetrievalError::BadJSONResponse)
+    }
+
+    fn reverse(&self, kind: ObjectKind, _id: u32) -> GraphInfoResult<Reverse> {
+        Ok(Reverse::IdAttribute { attribute: self.property(kind).to_string() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use idmap::{IdMapper, ObjectKind, Reverse};
+    use super::{Attribute, serde_json};
+
+    #[test]
+    fn integer_attribute_maps_both_ways() {
+        let attribute = Attribute::new("extension_uidNumber", "extension_gidNumber");
+        let object = serde_json::from_str("{\"extension_gidNumber\": 5000}").unwrap();
+        assert_eq!(attribute.id_for(ObjectKind::Group, &object).unwrap(), 5000);
+        match attribute.reverse(ObjectKind::Group, 5000).unwrap() {
+            Reverse::IdAttribute { attribute } => assert_eq!(attribute, "extension_gidNumber"),
+            _ => panic!("expected Reverse::IdAttribute"),
+        }
+    }
+
+    #[test]
+    fn string_attribute_is_read_as_a_number() {
+        let attribute = Attribute::new("extension_uidNumber", "extension_gidNumber");
+        let object = serde_json::from_str("{\"extension_uidNumber\": \"5000\"}").unwrap();
+        assert_eq!(attribute.id_for(ObjectKind::User, &object).unwrap(), 5000);
+    }
+
+    #[test]
+    fn missing_or_unusable_attribute_is_rejected() {
+        let attribute = Attribute::new("extension_uidNumber", "extension_gidNumber");
+        for json in &["{}", "{\"extension_uidNumber\": \"alice\"}",
+                      "{\"extension_uidNumber\": 5000000000}"] {
+            let object = serde_json::from_str(json).unwrap();
+            assert!(attribute.id_for(ObjectKind::User, &object).is_err());
+        }
+    }
+}
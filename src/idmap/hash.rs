@@ -0,0 +1,95 @@
+//! IDs made by hashing the object ID into a configured range.
+//!
+//! Every user and group has an object ID, so this works for cloud-only objects, but two objects
+//! may hash to the same ID, and finding the object for an ID means listing every object (which
+//! is done at most every `scan::SCAN_INTERVAL_SECS`).
+
+extern crate serde_json;
+
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use self::serde_json::Value;
+use super::{IdMapper, ObjectKind, Reverse};
+
+const FNV_OFFSET_BASIS: u32 = 0x811c9dc5;
+const FNV_PRIME: u32 = 0x01000193;
+
+pub struct Hash {
+    min: u32,
+    max: u32,
+}
+
+impl Hash {
+    pub fn new(min: u32, max: u32) -> Hash {
+        Hash { min, max }
+    }
+}
+
+/// The 32-bit FNV-1a hash of `data`, which (unlike std's hashers) is the same in every build.
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(FNV_OFFSET_BASIS,
+              |hash, byte| (hash ^ *byte as u32).wrapping_mul(FNV_PRIME))
+}
+
+impl IdMapper for Hash {
+    fn property(&self, _kind: ObjectKind) -> &str {
+        "id"
+    }
+
+    fn id_for(&self, _kind: ObjectKind, object: &Value) -> GraphInfoResult<u32> {
+        let object_id = object["id"]
+            .as_str()
+            .ok_or(GraphInfoRetrievalError::BadJSONResponse)?
+            .to_lowercase();
+        let size = self.max.saturating_sub(self.min) as u64 + 1;
+        Ok(self.min + (fnv1a(object_id.as_bytes()) as u64 % size) as u32)
+    }
+
+    fn reverse(&self, _kind: ObjectKind, id: u32) -> GraphInfoResult<Reverse> {
+        if id < self.min || id > self.max {
+            return Ok(Reverse::Unmapped);
+        }
+        Ok(Reverse::Scan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use idmap::{IdMapper, ObjectKind, Reverse};
+    use super::{Hash, fnv1a, serde_json};
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        assert_eq!(fnv1a(b""), 0x811c9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c292c);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9cf968);
+    }
+
+    #[test]
+    fn folds_hash_into_range_ignoring_case() {
+        let hash = Hash::new(100000, 100009);
+        let id_for = |object_id: &str| {
+            let object = serde_json::from_str(&format!("{{\"id\": \"{}\"}}", object_id)).unwrap();
+            hash.id_for(ObjectKind::User, &object).unwrap()
+        };
+        let object_id = "8A4B3C2D-1E0F-4A5B-9C8D-7E6F5A4B3C2D";
+        let expected = 100000 + fnv1a(object_id.to_lowercase().as_bytes()) % 10;
+        assert_eq!(id_for(object_id), expected);
+        assert_eq!(id_for(&object_id.to_lowercase()), expected);
+    }
+
+    #[test]
+    fn widest_valid_range_does_not_overflow() {
+        let hash = Hash::new(1000, u32::MAX);
+        let object = serde_json::from_str("{\"id\": \"foobar\"}").unwrap();
+        assert_eq!(hash.id_for(ObjectKind::Group, &object).unwrap(), 1000 + 0xbf9cf968);
+    }
+
+    #[test]
+    fn reverse_scans_only_within_range() {
+        let hash = Hash::new(100000, 199999);
+        assert!(matches!(hash.reverse(ObjectKind::User, 150000).unwrap(), Reverse::Scan));
+        assert!(matches!(hash.reverse(ObjectKind::User, 99999).unwrap(), Reverse::Unmapped));
+        assert!(matches!(hash.reverse(ObjectKind::User, 200000).unwrap(), Reverse::Unmapped));
+    }
+}
@@ -0,0 +1,166 @@
+//! Mapping between directory objects and UIDs/GIDs
+//!
+//! Each way of assigning IDs is an `IdMapper`, in a module of its own. `AadConfig` selects one
+//! with its `id_mapping` section. A mapper works in both directions: it gives the ID of a user or
+//! group fetched from Graph, and says how to find the user or group that has a given ID.
+
+extern crate serde_json;
+
+mod allocate;
+mod attribute;
+mod hash;
+mod rid;
+mod scan;
+
+use AadConfig;
+use error::GraphInfoResult;
+use self::serde_json::Value;
+
+pub use self::allocate::Allocate;
+pub use self::attribute::Attribute;
+pub use self::hash::Hash;
+pub use self::rid::{Domain, Rid};
+pub use self::scan::ScanIndex;
+
+/// Whether an ID is a UID or a GID.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum ObjectKind {
+    User,
+    Group,
+}
+
+/// How to find the directory object with a given ID.
+pub enum Reverse {
+    /// Ask Graph for the objects whose `property` equals `value`, an OData literal (quoted, if it
+    /// is a string).
+    Equals { property: String, value: String },
+    /// Ask Graph for the objects whose `attribute` holds the ID itself, whether as an integer or
+    /// as a string (see `posix::number`).
+    IdAttribute { attribute: String },
+    /// Nothing short of examining every object will do.
+    Scan,
+    /// No directory object can have this ID.
+    Unmapped,
+}
+
+pub trait IdMapper {
+    /// The property of a user or group that its ID is derived from, which must be selected in
+    /// every query.
+    fn property(&self, kind: ObjectKind) -> &str;
+
+    /// The UID or GID of `object`, a user or group from Graph.
+    fn id_for(&self, kind: ObjectKind, object: &Value) -> GraphInfoResult<u32>;
+
+    /// How to find the user or group with the given ID.
+    fn reverse(&self, kind: ObjectKind, id: u32) -> GraphInfoResult<Reverse>;
+}
+
+/// The `id_mapping` section of the configuration file, naming a `strategy` and its parameters.
+#[derive(Deserialize,Debug)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum IdMapConfig {
+    Rid {
+        #[serde(default)]
+        offset: u32,
+    },
+    Hash { min: u32, max: u32 },
+    Attribute {
+        user_attribute: String,
+        group_attribute: String,
+    },
+    Allocate { file: String, min: u32, max: u32 },
+}
+
+/// Run `f`, a lookup of a user or group by name that somebody asked for (rather than one that
+/// merely lists them), letting the configured IdMapper give new IDs to what it finds, if it is
+/// one that hands out IDs (see `allocate`).
+pub fn allocating<T, F>(config: &AadConfig, f: F) -> GraphInfoResult<T>
+    where F: FnOnce() -> GraphInfoResult<T>
+{
+    match config.id_mapping {
+        Some(IdMapConfig::Allocate { ref file, .. }) => allocate::allocating(file, f),
+        _ => f(),
+    }
+}
+
+/// Check that `min` and `max`, the range of the named strategy, are usable: every ID in it
+/// above the IDs reserved for local users and groups.
+fn check_range(strategy: &str, min: u32, max: u32) -> Result<(), String> {
+    if min > max {
+        return Err(format!("the {} ID mapping's min ({}) is above its max ({})",
+                           strategy,
+                           min,
+                           max));
+    }
+    if min < 1000 {
+        return Err(format!("the {} ID mapping's min ({}) is below 1000, which is reserved for \
+                            local users and groups",
+                           strategy,
+                           min));
+    }
+    Ok(())
+}
+
+impl AadConfig {
+    /// Check that the `id_mapping` (and, for the `rid` strategy, the `domains`) can map
+    /// anything at all, unambiguously, and into IDs that are not reserved.
+    pub fn check_id_mapping(&self) -> Result<(), String> {
+        match self.id_mapping {
+            Some(IdMapConfig::Rid { .. }) |
+            None => {
+                // Without any domains, only users with a uidNumber would have an ID.
+                if self.domains.is_empty() && self.domain_sid.is_none() &&
+                   self.posix_attributes.user.uid_number.is_none() {
+                    return Err("the rid ID mapping needs `domains` (or `domain_sid`)".to_string());
+                }
+                rid::check_domains(&self.domains)
+            }
+            Some(IdMapConfig::Hash { min, max }) => check_range("hash", min, max),
+            Some(IdMapConfig::Allocate { min, max, .. }) => check_range("allocate", min, max),
+            _ => Ok(()),
+        }
+    }
+
+    /// Build the IdMapper selected by this configuration.
+    ///
+    /// Configurations without an `id_mapping` section use the RID of the on-premises SID as the
+    /// ID, as earlier versions of this plugin did.
+    pub fn id_mapper<'a>(&'a self) -> Box<dyn IdMapper + 'a> {
+        match self.id_mapping {
+            Some(IdMapConfig::Rid { offset }) => self.rid_mapper(offset),
+            Some(IdMapConfig::Hash { min, max }) => Box::new(Hash::new(min, max)),
+            Some(IdMapConfig::Attribute { ref user_attribute, ref group_attribute }) => {
+                Box::new(Attribute::new(user_attribute, group_attribute))
+            }
+            Some(IdMapConfig::Allocate { ref file, min, max }) => {
+                Box::new(Allocate::new(file, min, max))
+            }
+            None => self.rid_mapper(0),
+        }
+    }
+
+    /// Map RIDs in the configured `domains`, or failing that in the single domain named by
+    /// `domain_sid`, whose range begins at `offset`.
+    fn rid_mapper<'a>(&'a self, offset: u32) -> Box<dyn IdMapper + 'a> {
+        match self.domain_sid {
+            Some(ref domain_sid) if self.domains.is_empty() => {
+                Box::new(Rid::single(domain_sid, offset))
+            }
+            _ => Box::new(Rid::new(&self.domains)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_range;
+
+    #[test]
+    fn range_must_be_ordered_and_above_reserved_ids() {
+        assert!(check_range("hash", 100000, 199999).is_ok());
+        assert!(check_range("hash", 1000, 1000).is_ok());
+        assert!(check_range("hash", 200000, 100000).is_err());
+        assert!(check_range("hash", 0, u32::MAX).is_err());
+        assert!(check_range("hash", 999, 100000).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! IDs taken from the relative ID (the last component) of the on-premises SID.
+//!
+//! Each on-premises domain has a range of IDs, starting at its `base`, and a SID's ID is its
+//! relative ID plus the base of its domain, as with winbind's idmap_rid. A domain's range ends
+//! where the next domain's begins. Only users and groups synced from on-premises AD have a SID,
+//! so cloud-only objects cannot be mapped this way, nor can objects from unlisted domains.
+
+extern crate serde_json;
+
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use self::serde_json::Value;
+use super::{IdMapper, ObjectKind, Reverse};
+
+const SID_PROPERTY: &str = "onPremisesSecurityIdentifier";
+
+/// RIDs below this belong to AD's built-in users and groups.
+const FIRST_USABLE_RID: u32 = 1000;
+
+/// An entry in the `domains` list of the configuration file.
+#[derive(Deserialize,Debug)]
+pub struct Domain {
+    sid: String,
+    #[serde(default)]
+    base: u32,
+}
+
+/// Check that `domains` have distinct SIDs and distinct bases, so that every domain has a range
+/// of its own.
+pub fn check_domains(domains: &[Domain]) -> Result<(), String> {
+    for (i, domain) in domains.iter().enumerate() {
+        for other in &domains[..i] {
+            if other.sid.eq_ignore_ascii_case(&domain.sid) {
+                return Err(format!("domain {} is listed more than once", domain.sid));
+            }
+            if other.base == domain.base {
+                return Err(format!("domains {} and {} have the same base, {}",
+                                   other.sid,
+                                   domain.sid,
+                                   domain.base));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub struct Rid<'a> {
+    /// (domain SID, base), in order of base
+    domains: Vec<(&'a str, u32)>,
+}
+
+impl<'a> Rid<'a> {
+    pub fn new(domains: &'a [Domain]) -> Rid<'a> {
+        Rid::with_domains(domains.iter().map(|d| (&d.sid[..], d.base)).collect())
+    }
+
+    /// A single domain, as configured by the top-level `domain_sid` of older configurations.
+    pub fn single(domain_sid: &'a str, base: u32) -> Rid<'a> {
+        Rid::with_domains(vec![(domain_sid, base)])
+    }
+
+    fn with_domains(mut domains: Vec<(&'a str, u32)>) -> Rid<'a> {
+        domains.sort_by_key(|&(_, base)| base);
+        Rid { domains }
+    }
+
+    /// The first ID beyond the range of the domain at `index`.
+    fn range_end(&self, index: usize) -> u64 {
+        match self.domains.get(index + 1) {
+            Some(&(_, base)) => base as u64,
+            None => u32::MAX as u64 + 1,
+        }
+    }
+}
+
+impl<'a> IdMapper for Rid<'a> {
+    fn property(&self, _kind: ObjectKind) -> &str {
+        SID_PROPERTY
+    }
+
+    fn id_for(&self, _kind: ObjectKind, object: &Value) -> GraphInfoResult<u32> {
+        let sid = object[SID_PROPERTY]
+            .as_str()
+            .ok_or(GraphInfoRetrievalError::BadJSONResponse)?;
+        let separator = sid.rfind('-').ok_or(GraphInfoRetrievalError::UnusableImmutableID)?;
+        let (domain_sid, rid) = (&sid[..separator], sid[separator + 1..].parse::<u32>()?);
+        if rid < FIRST_USABLE_RID {
+            return Err(GraphInfoRetrievalError::UnusableImmutableID);
+        }
+        let index = self.domains
+            .iter()
+            .position(|&(d, _)| d.eq_ignore_ascii_case(domain_sid))
+            .ok_or(GraphInfoRetrievalError::UnusableImmutableID)?;
+        let id = self.domains[index].1 as u64 + rid as u64;
+        if id >= self.range_end(index) {
+            return Err(GraphInfoRetrievalError::UnusableImmutableID);
+        }
+        Ok(id as u32)
+    }
+
+    fn reverse(&self, _kind: ObjectKind, id: u32) -> GraphInfoResult<Reverse> {
+        let index = match self.domains.iter().rposition(|&(_, base)| base <= id) {
+            Some(i) => i,
+            None => return Ok(Reverse::Unmapped),
+        };
+        let (domain_sid, base) = self.domains[index];
+        let rid = id - base;
+        if rid < FIRST_USABLE_RID {
+            return Ok(Reverse::Unmapped);
+        }
+        Ok(Reverse::Equals {
+               property: SID_PROPERTY.to_string(),
+               value: format!("'{}-{}'", domain_sid, rid),
+           })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use idmap::{IdMapper, ObjectKind, Reverse};
+    use super::{Domain, Rid, check_domains, serde_json};
+    use super::Value;
+
+    const CONTOSO: &str = "S-1-5-21-1004336348-1177238915-682003330";
+    const FABRIKAM: &str = "S-1-5-21-2127521184-1604012920-1887927527";
+
+    fn domain(sid: &str, base: u32) -> Domain {
+        Domain {
+            sid: sid.to_string(),
+            base,
+        }
+    }
+
+    fn object(sid: &str) -> Value {
+        serde_json::from_str(&format!("{{\"onPremisesSecurityIdentifier\": \"{}\"}}", sid))
+            .unwrap()
+    }
+
+    fn id_for(rid: &Rid, sid: &str) -> Option<u32> {
+        rid.id_for(ObjectKind::User, &object(sid)).ok()
+    }
+
+    fn reverse(rid: &Rid, id: u32) -> Option<String> {
+        match rid.reverse(ObjectKind::User, id).unwrap() {
+            Reverse::Equals { property, value } => {
+                assert_eq!(property, "onPremisesSecurityIdentifier");
+                Some(value)
+            }
+            Reverse::Scan | Reverse::IdAttribute { .. } => panic!("rid mapping only filters SIDs"),
+            Reverse::Unmapped => None,
+        }
+    }
+
+    #[test]
+    fn maps_each_domain_into_its_own_range() {
+        let domains = [domain(FABRIKAM, 200000), domain(CONTOSO, 100000)];
+        let rid = Rid::new(&domains);
+        assert_eq!(id_for(&rid, &format!("{}-1105", CONTOSO)), Some(101105));
+        assert_eq!(id_for(&rid, &format!("{}-1105", FABRIKAM.to_lowercase())), Some(201105));
+        assert_eq!(reverse(&rid, 101105), Some(format!("'{}-1105'", CONTOSO)));
+        assert_eq!(reverse(&rid, 201105), Some(format!("'{}-1105'", FABRIKAM)));
+    }
+
+    #[test]
+    fn rejects_ids_outside_the_ranges() {
+        let domains = [domain(CONTOSO, 100000), domain(FABRIKAM, 200000)];
+        let rid = Rid::new(&domains);
+        // Built-in accounts, RIDs that overflow into the next domain and unlisted domains
+        assert_eq!(id_for(&rid, &format!("{}-500", CONTOSO)), None);
+        assert_eq!(id_for(&rid, &format!("{}-100000", CONTOSO)), None);
+        assert_eq!(id_for(&rid, "S-1-5-21-1-2-3-1105"), None);
+        assert_eq!(id_for(&rid, CONTOSO), None);
+        assert_eq!(reverse(&rid, 1000), None);
+        assert_eq!(reverse(&rid, 100500), None);
+    }
+
+    #[test]
+    fn last_domain_extends_to_the_largest_id() {
+        let rid = Rid::single(CONTOSO, 4000000000);
+        assert_eq!(id_for(&rid, &format!("{}-294967295", CONTOSO)), Some(u32::MAX));
+        assert_eq!(id_for(&rid, &format!("{}-294967296", CONTOSO)), None);
+        assert_eq!(reverse(&rid, u32::MAX), Some(format!("'{}-294967295'", CONTOSO)));
+    }
+
+    #[test]
+    fn check_domains_rejects_duplicates() {
+        assert!(check_domains(&[domain(CONTOSO, 100000), domain(FABRIKAM, 200000)]).is_ok());
+        assert!(check_domains(&[domain(CONTOSO, 100000),
+                                domain(&CONTOSO.to_lowercase(), 200000)])
+                        .is_err());
+        assert!(check_domains(&[domain(CONTOSO, 100000), domain(FABRIKAM, 100000)]).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! An index of every object's ID, for finding objects with `Reverse::Scan`.
+//!
+//! Where a mapper can only find the object for an ID by examining every object, every lookup
+//! of an ID would list the whole tenant, and `ls -l` in a directory full of unknown IDs would
+//! list it once for each file. Instead, the IDs found by one listing are kept for a while, and
+//! lookups of IDs in the meantime are answered from them: an ID that nobody had is not found
+//! without asking Graph at all, and one that somebody had costs a single lookup of its owner.
+
+use error::GraphInfoResult;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a listing is used for before the objects are listed again. Objects created in the
+/// meantime cannot be found by ID until then.
+pub const SCAN_INTERVAL_SECS: u64 = 300;
+
+pub struct ScanIndex {
+    listing: Mutex<Option<Listing>>,
+}
+
+/// The object IDs of every object with each ID, as of `listed`
+struct Listing {
+    listed: Instant,
+    object_ids: HashMap<u32, Vec<String>>,
+}
+
+impl ScanIndex {
+    pub fn new() -> ScanIndex {
+        ScanIndex { listing: Mutex::new(None) }
+    }
+
+    /// The object IDs of the objects with ID `id`, from the current listing, or from a new one
+    /// made by `list` (giving each object's ID and object ID) if the current one is too old.
+    ///
+    /// Only one listing is made at a time; concurrent lookups wait for it rather than making
+    /// their own.
+    pub fn find<F>(&self, id: u32, list: F) -> GraphInfoResult<Vec<String>>
+        where F: FnOnce() -> GraphInfoResult<Vec<(u32, String)>>
+    {
+        let mut listing = self.listing.lock().unwrap_or_else(|e| e.into_inner());
+        let fresh = listing
+            .as_ref()
+            .is_some_and(|l| l.listed.elapsed() < Duration::from_secs(SCAN_INTERVAL_SECS));
+        if !fresh {
+            let mut object_ids: HashMap<u32, Vec<String>> = HashMap::new();
+            for (id, object_id) in list()? {
+                object_ids.entry(id).or_default().push(object_id);
+            }
+            *listing = Some(Listing {
+                                listed: Instant::now(),
+                                object_ids,
+                            });
+        }
+        Ok(listing
+               .as_ref()
+               .and_then(|l| l.object_ids.get(&id))
+               .cloned()
+               .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::GraphInfoRetrievalError;
+    use std::cell::Cell;
+    use super::ScanIndex;
+
+    #[test]
+    fn lists_once_per_interval() {
+        let index = ScanIndex::new();
+        let listings = Cell::new(0);
+        let list = || {
+            listings.set(listings.get() + 1);
+            Ok(vec![(1000, "a".to_string()), (1001, "b".to_string()), (1000, "c".to_string())])
+        };
+        assert_eq!(index.find(1000, list).unwrap(), vec!["a", "c"]);
+        assert_eq!(index.find(1001, list).unwrap(), vec!["b"]);
+        assert!(index.find(1002, list).unwrap().is_empty());
+        assert_eq!(listings.get(), 1);
+    }
+
+    #[test]
+    fn failed_listing_is_not_kept() {
+        let index = ScanIndex::new();
+        assert!(index.find(1000, || Err(GraphInfoRetrievalError::NotFound)).is_err());
+        assert_eq!(index.find(1000, || Ok(vec![(1000, "a".to_string())])).unwrap(), vec!["a"]);
+    }
+}
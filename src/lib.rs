@@ -2,6 +2,15 @@
 //!
 //! The public functions in this library do not form a comprehensive implementation of an
 //! NSS plugin, but only provide the minimum necessary for the author's use cases.
+//!
+//! The plugin itself never talks to AAD. It passes each lookup to nss-aad-daemon over a Unix
+//! socket (see `client` and `protocol`), and the daemon (see `daemon` and `lookup`) is the only
+//! process that reads the configuration and queries the directory.
+
+// serde_derive 0.9 wraps each impl it generates in a constant
+#![allow(non_local_definitions)]
+// The _nss_aad_* functions are only called by glibc, which passes them valid pointers
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 
 extern crate core;
 extern crate libc;
@@ -10,20 +19,42 @@ extern crate libc;
 extern crate serde_derive;
 
 extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
+extern crate serde;
 extern crate serde_yaml;
 
+mod auth;
 mod azure;
+mod client;
+mod cloud;
+pub mod daemon;
 mod error;
+mod fsutil;
+mod idmap;
+mod local_groups;
+mod lookup;
+mod members;
+mod names;
+mod negative_cache;
+mod offline_cache;
+mod posix;
+mod protocol;
+mod templates;
+mod tls;
+mod token_cache;
 
 use core::ptr::null_mut;
-use error::{GraphInfoRetrievalError, BufferFillError, BufferFillResult};
-use hyper::status::StatusCode;
-use libc::{c_void, c_char, uid_t, gid_t, size_t, passwd, group};
-use libc::{ENOENT, EAGAIN, ERANGE};
+use error::{BufferFillError, BufferFillResult};
+use libc::{c_void, c_char, c_int, c_long, uid_t, gid_t, size_t, passwd, group, spwd};
+use libc::{ENOENT, EAGAIN, ENOMEM, ERANGE};
+use protocol::{GroupEntry, PasswdEntry, Request, Response, ShadowEntry};
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io::prelude::*;
 use std::ptr::copy_nonoverlapping;
+use std::sync::Mutex;
 
 /// NssStatus is the return value from libnss-called functions; they are cast to i32 when being
 /// returned.
@@ -36,35 +67,137 @@ enum NssStatus {
 
 #[derive(Deserialize,Debug)]
 pub struct AadConfig {
-    client_id: String,
-    client_secret: String,
-    domain_sid: String,
+    #[serde(default)]
+    auth: Option<auth::AuthConfig>,
+    #[serde(default)]
+    client_id: Option<String>,
+    #[serde(default)]
+    client_secret: Option<String>,
+    #[serde(default)]
+    domain_sid: Option<String>,
+    #[serde(default)]
+    domains: Vec<idmap::Domain>,
     default_user_group_id: u32,
-    tenant: String
+    tenant: String,
+    #[serde(default)]
+    cloud: cloud::Cloud,
+    #[serde(default)]
+    authority: Option<String>,
+    #[serde(default)]
+    graph_endpoint: Option<String>,
+    #[serde(default)]
+    token_cache_file: Option<String>,
+    #[serde(default)]
+    offline_cache: Option<offline_cache::OfflineCacheConfig>,
+    #[serde(default)]
+    negative_cache_ttl: u64,
+    #[serde(default)]
+    enumerate: bool,
+    #[serde(default)]
+    shadow: bool,
+    #[serde(default)]
+    shadow_expire_attribute: Option<String>,
+    #[serde(default)]
+    transitive_groups: bool,
+    #[serde(default)]
+    group_members: members::GroupMembersConfig,
+    #[serde(default)]
+    id_mapping: Option<idmap::IdMapConfig>,
+    #[serde(default)]
+    posix_attributes: posix::PosixAttributesConfig,
+    #[serde(default)]
+    short_names: Option<names::ShortNamesConfig>,
+    #[serde(default)]
+    netbios_domains: HashMap<String, String>,
+    #[serde(default)]
+    templates: templates::TemplatesConfig,
+    #[serde(default)]
+    group_overrides: Vec<templates::GroupOverride>,
+    #[serde(default)]
+    user_private_groups: bool,
+    #[serde(default)]
+    primary_groups: Vec<String>,
+    #[serde(default)]
+    local_groups: HashMap<String, String>,
 }
 
 impl AadConfig {
     /// Helper function to initialize an AadConfig from the named file.
-    fn from_file(filename: &str) -> serde_yaml::Result<AadConfig> {
+    pub fn from_file(filename: &str) -> serde_yaml::Result<AadConfig> {
         let mut file = File::open(filename)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
 
-        serde_yaml::from_str(&contents)
+        let config: AadConfig = serde_yaml::from_str(&contents)?;
+        config
+            .check_id_mapping()
+            .map_err(<serde_yaml::Error as serde::de::Error>::custom)?;
+        config
+            .check_group_ids()
+            .map_err(<serde_yaml::Error as serde::de::Error>::custom)?;
+        Ok(config)
+    }
+
+    /// Check that the groups that grant anything are named by object ID (see
+    /// `names::check_object_ids`).
+    fn check_group_ids(&self) -> Result<(), String> {
+        names::check_object_ids("group_overrides", self.group_overrides.iter().map(|o| &o.group))?;
+        names::check_object_ids("primary_groups", &self.primary_groups)?;
+        names::check_object_ids("local_groups", self.local_groups.keys())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct UserInfo {
     username: String,
+    upn: String,
+    object_id: String,
+    /// The on-premises domain's DNS name, for users synced from AD
+    domain: Option<String>,
     fullname: String,
+    office: Option<String>,
+    phone: Option<String>,
     userid: u32, // too platform-specific? should this be something else?
+    enabled: bool,
+    /// Days since the epoch, as in the shadow database
+    password_changed: Option<i64>,
+    expires: Option<i64>,
+    /// POSIX attributes from the directory, where configured and present
+    groupid: Option<u32>,
+    shell: Option<String>,
+    home: Option<String>,
+    gecos: Option<String>,
+}
+
+#[cfg(test)]
+impl UserInfo {
+    /// A user with the given name and UID, with nothing else known about them, for tests.
+    fn test_user(username: &str, userid: u32) -> UserInfo {
+        UserInfo {
+            username: username.to_string(),
+            upn: format!("{}@contoso.com", username),
+            object_id: format!("00000000-0000-0000-0000-{:012}", userid),
+            domain: None,
+            fullname: username.to_string(),
+            office: None,
+            phone: None,
+            userid,
+            enabled: true,
+            password_changed: None,
+            expires: None,
+            groupid: None,
+            shell: None,
+            home: None,
+            gecos: None,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct GroupInfo {
     groupname: String,
     object_id: String,
+    domain: Option<String>,
     group_id: u32
 }
 
@@ -86,7 +219,7 @@ pub extern "C" fn _nss_aad_initgroups_dyn(name: *const c_char,
                                           skipgroup: gid_t,
                                           start: *mut size_t,
                                           size: *mut size_t,
-                                          mut groupsp: *mut *mut gid_t,
+                                          groupsp: *mut *mut gid_t,
                                           limit: size_t,
                                           errnop: *mut i32)
                                           -> i32 {
@@ -102,26 +235,16 @@ pub extern "C" fn _nss_aad_initgroups_dyn(name: *const c_char,
     #[cfg(debug_assertions)]
     println!("libnss-aad initgroups_dyn called for {}", name);
 
-    let config = match AadConfig::from_file("/etc/nssaad.conf") {
-        Ok(c) => c,
-        Err(_) => {
-            return nss_input_file_err(errnop);
-        }
-    };
-
-    // Get the user's groups, keeping the GIDs of only those groups appearing in the config file,
-    // and that are not equal to `skipgroup`.
-    #[allow(unused_variables)]
-    let mut user_groups: Vec<gid_t> = match azure::get_user_groups(&config, name) {
-            Ok(v) => v,
-            Err(err) => {
+    // Get the user's groups, keeping the GIDs of only those that are not equal to `skipgroup`.
+    let user_groups: Vec<gid_t> = match client::query(&Request::InitGroups(name.to_string())) {
+            Response::Groups(v) => v,
+            response => {
                 #[cfg(debug_assertions)]
-                println!("libnss-aad failed to get user groups: {:?}", err);
-                return nss_entry_not_available(errnop);
+                println!("libnss-aad failed to get user groups for {}", name);
+                return nss_lookup_failed(response, errnop);
             }
         }
-        .iter()
-        .map(|g| g.group_id)
+        .into_iter()
         .filter(|&gid| gid != skipgroup)
         .collect();
 
@@ -130,8 +253,6 @@ pub extern "C" fn _nss_aad_initgroups_dyn(name: *const c_char,
         #[cfg(debug_assertions)]
         println!("libnss-aad got no user groups for {}", name);
         return NssStatus::Success as i32;
-    } else {
-        user_groups.push(config.default_user_group_id);
     }
 
     // How big is the array we were passed, and how deep into it are we?
@@ -145,8 +266,17 @@ pub extern "C" fn _nss_aad_initgroups_dyn(name: *const c_char,
     if idx + user_groups.len() > group_arraysz {
         // We need to add more group IDs to the array than we currently have space for
         let new_sz = std::cmp::min(idx + user_groups.len(), limit);
+        let new_groups = unsafe {
+            libc::realloc(*groupsp as *mut c_void,
+                          new_sz * std::mem::size_of::<gid_t>()) as *mut gid_t
+        };
+        if new_groups.is_null() {
+            // The original array is still valid, and still the caller's to free
+            unsafe { *errnop = ENOMEM };
+            return NssStatus::TryAgain as i32;
+        }
         unsafe {
-            *groupsp = libc::realloc(*groupsp as *mut c_void, new_sz) as *mut gid_t;
+            *groupsp = new_groups;
             *size = new_sz;
         }
         group_arraysz = new_sz;
@@ -158,14 +288,14 @@ pub extern "C" fn _nss_aad_initgroups_dyn(name: *const c_char,
         unsafe { std::slice::from_raw_parts_mut(*groupsp, group_arraysz) };
 
     for gid in user_groups {
+        if idx >= group_arraysz {
+            // if we run out of space (having reached `limit`), bail
+            break;
+        }
         // Copy the GID into the raw slice
         group_array[idx] = gid;
         // keeping track of the index (which must be returned to the caller)
         idx += 1;
-        if idx == limit {
-            // if we run out of space, bail
-            break;
-        }
     }
 
     unsafe {
@@ -182,7 +312,7 @@ pub extern "C" fn _nss_aad_initgroups_dyn(name: *const c_char,
 /// of member pointers. The information that is looked up is stored in `buffer`, and `result`'s
 /// pointers point into the buffer.
 ///
-/// The group's GID is looked up in the configuration.
+/// The group is looked up in the directory by nss-aad-daemon.
 #[no_mangle]
 pub extern "C" fn _nss_aad_getgrnam_r(name: *const c_char,
                                       result: *mut group,
@@ -202,61 +332,28 @@ pub extern "C" fn _nss_aad_getgrnam_r(name: *const c_char,
     #[cfg(debug_assertions)]
     println!("libnss-aad getgrnam_r called for {}", name);
 
-    let config = match AadConfig::from_file("/etc/nssaad.conf") {
-        Ok(c) => c,
-        Err(_) => {
-            return nss_input_file_err(errnop);
-        }
-    };
-
-    // Get the attributes of the group. Specifically we need its object ID.
-    let groupinfo = match azure::get_group_info(&config, name) {
-        Ok(i) => i,
-        Err(e) => {
-            match e {
-                GraphInfoRetrievalError::BadHTTPResponse { status, .. } => {
-                    match status {
-                        StatusCode::NotFound => {
-                            #[cfg(debug_assertion)]
-                            println!("libnss-aad getgrnam could not find {}", name);
-                            return nss_entry_not_available(errnop);
-                        }
-                        _ => {
-                            return nss_out_of_service(errnop);
-                        }
-                    }
-                }
-                GraphInfoRetrievalError::TooManyResults |
-                GraphInfoRetrievalError::NotFound => {
-                    return nss_entry_not_available(errnop);
-                }
-                _ => {
-                    return nss_out_of_service(errnop);
-                }
-            };
-        }
-    };
-
-    // Look up members of the group, using the group's object ID
-    let groupmembers: Vec<UserInfo> = match azure::get_group_members(&config,
-                                                                     &groupinfo.object_id) {
-        Ok(m) => m,
-        _ => vec![],
-    };
+    match client::query(&Request::GetGrNam(name.to_string())) {
+        Response::Group(entry) => group_lookup_succeeded(result, buffer, buflen, &entry, errnop),
+        response => nss_lookup_failed(response, errnop),
+    }
+}
 
-    match fill_group_buf(result,
-                         groupinfo.group_id as gid_t,
-                         buffer,
-                         buflen,
-                         name,
-                         &groupmembers) {
+/// Fill in `result` from `entry`, and return the appropriate status for a successful getgr*
+/// lookup.
+fn group_lookup_succeeded(result: *mut group,
+                          buffer: *mut c_char,
+                          buflen: size_t,
+                          entry: &GroupEntry,
+                          errnop: *mut i32)
+                          -> i32 {
+    match fill_group_buf(result, buffer, buflen, entry) {
         Ok(()) => NssStatus::Success as i32,
         Err(e) => {
             match e {
                 BufferFillError::InsufficientBuffer => nss_insufficient_buffer(errnop),
                 _ => {
                     #[cfg(debug_assertions)]
-                    println!("libnss-aad getgrnam_r failed because {:?}", e);
+                    println!("libnss-aad getgr* failed because {:?}", e);
                     nss_entry_not_available(errnop)
                 }
             }
@@ -275,27 +372,21 @@ pub extern "C" fn _nss_aad_getgrnam_r(name: *const c_char,
 ///
 /// Modifies `grp` and `buffer`.
 fn fill_group_buf(grp: *mut group,
-                  gid: gid_t,
                   buffer: *mut c_char,
                   buflen: size_t,
-                  name: &str,
-                  members: &[UserInfo])
+                  entry: &GroupEntry)
                   -> BufferFillResult<()> {
-    #[cfg(debug_assertion)]
-    println!("filling group buffer for group {} which has {} members",
-             name,
-             members.len());
-
     // name and passwd are easy - we can copy them straight into the provided buffer
-    let c_name = CString::new(name)?.into_bytes_with_nul();
+    let c_name = CString::new(&entry.name[..])?.into_bytes_with_nul();
     let c_gpasswd = CString::new("!")?.into_bytes_with_nul();
 
     // members are harder - we need to provide a pointer to the base of a vector of pointers
     // c_members is a vector of names (which are themselves vectors of bytes)
-    let c_members = members
+    let c_members = entry
+        .members
         .iter()
-        .map(|m: &UserInfo| {
-                 let c_member = CString::new(m.username.clone()).unwrap();
+        .map(|m: &String| {
+                 let c_member = CString::new(&m[..]).unwrap();
                  c_member.into_bytes_with_nul()
              })
         .collect::<Vec<Vec<u8>>>();
@@ -318,10 +409,10 @@ fn fill_group_buf(grp: *mut group,
         // grp member pointers accordingly.
         copy_nonoverlapping(c_name.as_ptr(), buf_cur as *mut u8, c_name.len());
         (*grp).gr_name = buf_cur;
-        buf_cur = buf_cur.offset(c_name.len() as isize);
+        buf_cur = buf_cur.add(c_name.len());
         copy_nonoverlapping(c_gpasswd.as_ptr(), buf_cur as *mut u8, c_gpasswd.len());
         (*grp).gr_passwd = buf_cur;
-        buf_cur = buf_cur.offset(c_gpasswd.len() as isize);
+        buf_cur = buf_cur.add(c_gpasswd.len());
     }
 
     // Now the harder stuff.
@@ -336,7 +427,7 @@ fn fill_group_buf(grp: *mut group,
         c_member_ptrs.push(buf_cur);
         // and move the cursor
         unsafe {
-            buf_cur = buf_cur.offset(c_member.len() as isize);
+            buf_cur = buf_cur.add(c_member.len());
         }
     }
     // the last item in the vector of pointers should be a null pointer
@@ -355,7 +446,7 @@ fn fill_group_buf(grp: *mut group,
 
     // copy the gid value into the grp object
     unsafe {
-        (*grp).gr_gid = gid;
+        (*grp).gr_gid = entry.gid as gid_t;
     }
 
     Ok(())
@@ -367,8 +458,8 @@ fn fill_group_buf(grp: *mut group,
 /// of member pointers. The information that is looked up is stored in `buffer`, and `result`'s
 /// pointers point into the buffer.
 ///
-/// The group name is looked up in the configuration. The first matching result is returned (that
-/// is, duplicated GIDs are ignored).
+/// The group is looked up in the directory by nss-aad-daemon. If more than one group has the
+/// GID, no group is returned.
 #[no_mangle]
 pub extern "C" fn _nss_aad_getgrgid_r(gid: gid_t,
                                       result: *mut group,
@@ -386,62 +477,9 @@ pub extern "C" fn _nss_aad_getgrgid_r(gid: gid_t,
     #[cfg(debug_assertions)]
     println!("libnss-aad getgrgid_r called for {}", gid);
 
-    let config = match AadConfig::from_file("/etc/nssaad.conf") {
-        Ok(c) => c,
-        Err(_) => {
-            return nss_input_file_err(errnop);
-        }
-    };
-
-    let sid = format!("{}-{}", config.domain_sid, gid);
-
-    // Get the attributes of the group. Specifically we need its object ID.
-    let groupinfo = match azure::get_group_info_by_sid(&config, &sid) {
-        Ok(i) => i,
-        Err(e) => {
-            match e {
-                GraphInfoRetrievalError::BadHTTPResponse { status, .. } => {
-                    match status {
-                        StatusCode::NotFound => {
-                            #[cfg(debug_assertion)]
-                            println!("libnss-aad getgrgid could not find {}", name);
-                            return nss_entry_not_available(errnop);
-                        }
-                        _ => {
-                            return nss_out_of_service(errnop);
-                        }
-                    }
-                }
-                GraphInfoRetrievalError::TooManyResults |
-                GraphInfoRetrievalError::NotFound => {
-                    return nss_entry_not_available(errnop);
-                }
-                _ => {
-                    return nss_out_of_service(errnop);
-                }
-            };
-        }
-    };
-
-    // Look up members of the group, using the group's object ID
-    let groupmembers: Vec<UserInfo> = match azure::get_group_members(&config,
-                                                                     &groupinfo.object_id) {
-        Ok(m) => m,
-        _ => vec![],
-    };
-
-    match fill_group_buf(result, gid, buffer, buflen, &groupinfo.groupname, &groupmembers) {
-        Ok(()) => NssStatus::Success as i32,
-        Err(e) => {
-            match e {
-                BufferFillError::InsufficientBuffer => nss_insufficient_buffer(errnop),
-                _ => {
-                    #[cfg(debug_assertions)]
-                    println!("libnss-aad getgrgid_r failed because {:?}", e);
-                    nss_entry_not_available(errnop)
-                }
-            }
-        }
+    match client::query(&Request::GetGrGid(gid)) {
+        Response::Group(entry) => group_lookup_succeeded(result, buffer, buflen, &entry, errnop),
+        response => nss_lookup_failed(response, errnop),
     }
 }
 
@@ -464,57 +502,11 @@ pub extern "C" fn _nss_aad_getpwuid_r(uid: uid_t,
     #[cfg(debug_assertions)]
     println!("libnss-aad getpwuid_r called for {}", uid);
 
-    let config = match AadConfig::from_file("/etc/nssaad.conf") {
-        Ok(c) => c,
-        Err(_) => {
-            return nss_input_file_err(errnop);
-        }
-    };
-
-    let sid = format!("{}-{}", config.domain_sid, uid);
-
-    let userinfo = match azure::get_user_info_by_sid(&config, &sid) {
-        Ok(i) => i,
-        Err(e) => {
-            match e {
-                GraphInfoRetrievalError::BadHTTPResponse { status, .. } => {
-                    match status {
-                        StatusCode::NotFound => {
-                            #[cfg(debug_assertion)]
-                            println!("libnss-aad getpwuid could not find {}", uid);
-                            return nss_entry_not_available(errnop);
-                        }
-                        _ => {
-                            return nss_out_of_service(errnop);
-                        }
-                    }
-                }
-                GraphInfoRetrievalError::TooManyResults |
-                GraphInfoRetrievalError::NotFound => {
-                    return nss_entry_not_available(errnop);
-                }
-                _ => {
-                    return nss_out_of_service(errnop);
-                }
-            };
-        }
-    };
-
-    unsafe {
-        (*pw).pw_uid = userinfo.userid as uid_t;
-        (*pw).pw_gid = config.default_user_group_id as gid_t;
+    match client::query(&Request::GetPwUid(uid)) {
+        Response::Passwd(entry) => passwd_lookup_succeeded(pw, buffer, buflen, &entry, errnop),
+        response => nss_lookup_failed(response, errnop),
     }
-
-    match fill_passwd_buf(pw, buffer, buflen, &userinfo.username, userinfo.fullname) {
-        Ok(()) => NssStatus::Success as i32,
-        Err(e) => {
-            match e {
-                BufferFillError::ZeroByteInString => nss_entry_not_available(errnop),
-                _ => nss_insufficient_buffer(errnop),
-            }
-        }
-    }
-}					
+}
 
 /// getpwnam returns information about the named user
 ///
@@ -541,40 +533,20 @@ pub extern "C" fn _nss_aad_getpwnam_r(name: *const c_char,
     #[cfg(debug_assertions)]
     println!("libnss-aad getpwnam_r called for {}", name);
 
-    let config = match AadConfig::from_file("/etc/nssaad.conf") {
-        Ok(c) => c,
-        Err(_) => {
-            return nss_input_file_err(errnop);
-        }
-    };
-
-    let userinfo = match azure::get_user_info(&config, name) {
-        Ok(i) => i,
-        Err(e) => {
-            match e {
-                GraphInfoRetrievalError::BadHTTPResponse { status, .. } => {
-                    match status {
-                        StatusCode::NotFound => {
-                            return nss_entry_not_available(errnop);
-                        }
-                        _ => {
-                            return nss_out_of_service(errnop);
-                        }
-                    }
-                }
-                _ => {
-                    return nss_out_of_service(errnop);
-                }
-            };
-        }
-    };
-
-    unsafe {
-        (*pw).pw_uid = userinfo.userid as uid_t;
-        (*pw).pw_gid = config.default_user_group_id as gid_t;
+    match client::query(&Request::GetPwNam(name.to_string())) {
+        Response::Passwd(entry) => passwd_lookup_succeeded(pw, buffer, buflen, &entry, errnop),
+        response => nss_lookup_failed(response, errnop),
     }
+}
 
-    match fill_passwd_buf(pw, buffer, buflen, &userinfo.username, userinfo.fullname) {
+/// Fill in `pw` from `entry`, and return the appropriate status for a successful getpw* lookup.
+fn passwd_lookup_succeeded(pw: *mut passwd,
+                           buffer: *mut c_char,
+                           buflen: size_t,
+                           entry: &PasswdEntry,
+                           errnop: *mut i32)
+                           -> i32 {
+    match fill_passwd_buf(pw, buffer, buflen, entry) {
         Ok(()) => NssStatus::Success as i32,
         Err(e) => {
             match e {
@@ -585,7 +557,6 @@ pub extern "C" fn _nss_aad_getpwnam_r(name: *const c_char,
     }
 }
 
-
 /// This function accepts Rust structures and copies their contents into the buffer provided to
 /// store the contents of the provided C struct passwd.
 ///
@@ -597,23 +568,22 @@ pub extern "C" fn _nss_aad_getpwnam_r(name: *const c_char,
 ///
 /// Modifies `pw` and `buffer`.
 ///
-/// This arbitrarily sets the password field of the `pw` struct to `.` because OpenSSH interprets
-/// `*` as indicating a locked account.
+/// The password field of the `pw` struct is `entry.passwd`: `x` if `shadow` is enabled, so that
+/// sshd and pam_unix consult the shadow entry (see `fill_shadow_buf`) to tell whether the account
+/// is locked or expired, or else `.`.
 fn fill_passwd_buf(pw: *mut passwd,
                    buffer: *mut c_char,
                    buflen: size_t,
-                   username: &str,
-                   fullname: String)
+                   entry: &PasswdEntry)
                    -> BufferFillResult<()> {
     if pw.is_null() || buffer.is_null() || buflen == 0 {
         return Err(BufferFillError::NullPointerError);
     }
-    let c_name = CString::new(username)?.into_bytes_with_nul();
-    let c_passwd = CString::new(".")?.into_bytes_with_nul();
-    let c_gecos = CString::new(fullname)?.into_bytes_with_nul();
-    let c_dir = CString::new(format!("/home/{}", username))?
-        .into_bytes_with_nul();
-    let c_shell = CString::new("/bin/bash")?.into_bytes_with_nul();
+    let c_name = CString::new(&entry.name[..])?.into_bytes_with_nul();
+    let c_passwd = CString::new(&entry.passwd[..])?.into_bytes_with_nul();
+    let c_gecos = CString::new(&entry.gecos[..])?.into_bytes_with_nul();
+    let c_dir = CString::new(&entry.dir[..])?.into_bytes_with_nul();
+    let c_shell = CString::new(&entry.shell[..])?.into_bytes_with_nul();
 
     if buflen < c_name.len() + c_passwd.len() + c_gecos.len() + c_dir.len() + c_shell.len() {
         return Err(BufferFillError::InsufficientBuffer);
@@ -621,21 +591,24 @@ fn fill_passwd_buf(pw: *mut passwd,
 
     let mut buf_cur = buffer;
     unsafe {
+        (*pw).pw_uid = entry.uid as uid_t;
+        (*pw).pw_gid = entry.gid as gid_t;
+
         copy_nonoverlapping(c_name.as_ptr(), buf_cur as *mut u8, c_name.len());
         (*pw).pw_name = buf_cur;
-        buf_cur = buf_cur.offset(c_name.len() as isize);
+        buf_cur = buf_cur.add(c_name.len());
 
         copy_nonoverlapping(c_passwd.as_ptr(), buf_cur as *mut u8, c_passwd.len());
         (*pw).pw_passwd = buf_cur;
-        buf_cur = buf_cur.offset(c_passwd.len() as isize);
+        buf_cur = buf_cur.add(c_passwd.len());
 
         copy_nonoverlapping(c_gecos.as_ptr(), buf_cur as *mut u8, c_gecos.len());
         (*pw).pw_gecos = buf_cur;
-        buf_cur = buf_cur.offset(c_gecos.len() as isize);
+        buf_cur = buf_cur.add(c_gecos.len());
 
         copy_nonoverlapping(c_shell.as_ptr(), buf_cur as *mut u8, c_shell.len());
         (*pw).pw_shell = buf_cur;
-        buf_cur = buf_cur.offset(c_shell.len() as isize);
+        buf_cur = buf_cur.add(c_shell.len());
 
         copy_nonoverlapping(c_dir.as_ptr(), buf_cur as *mut u8, c_dir.len());
         (*pw).pw_dir = buf_cur;
@@ -644,6 +617,255 @@ fn fill_passwd_buf(pw: *mut passwd,
     Ok(())
 }
 
+/// getspnam returns the shadow entry for the named user
+///
+/// The shadow entry carries no password, since AAD never gives those out, but it does say
+/// whether the account is disabled (locked and expired) or due to expire, for the benefit of
+/// sshd, pam_unix's account checks, `passwd -S` and `chage -l`.
+#[no_mangle]
+pub extern "C" fn _nss_aad_getspnam_r(name: *const c_char,
+                                      sp: *mut spwd,
+                                      buffer: *mut c_char,
+                                      buflen: size_t,
+                                      errnop: *mut i32)
+                                      -> i32 {
+
+    assert!(!sp.is_null() && !buffer.is_null() && !errnop.is_null());
+
+    let name = match unsafe { CStr::from_ptr(name) }.to_str() {
+        Ok(s) => s,
+        Err(_) => {
+            return nss_entry_not_available(errnop);
+        }
+    };
+
+    #[cfg(debug_assertions)]
+    println!("libnss-aad getspnam_r called for {}", name);
+
+    match client::query(&Request::GetSpNam(name.to_string())) {
+        Response::Shadow(entry) => {
+            match fill_shadow_buf(sp, buffer, buflen, &entry) {
+                Ok(()) => NssStatus::Success as i32,
+                Err(BufferFillError::ZeroByteInString) => nss_entry_not_available(errnop),
+                Err(_) => nss_insufficient_buffer(errnop),
+            }
+        }
+        response => nss_lookup_failed(response, errnop),
+    }
+}
+
+/// Copy a ShadowEntry into the buffer provided for the C struct spwd, as fill_passwd_buf does
+/// for struct passwd.
+///
+/// Password aging is left to AAD, so the minimum, maximum, warning and inactivity periods are
+/// all left unset.
+///
+/// Modifies `sp` and `buffer`.
+fn fill_shadow_buf(sp: *mut spwd,
+                   buffer: *mut c_char,
+                   buflen: size_t,
+                   entry: &ShadowEntry)
+                   -> BufferFillResult<()> {
+    if sp.is_null() || buffer.is_null() || buflen == 0 {
+        return Err(BufferFillError::NullPointerError);
+    }
+    let c_name = CString::new(&entry.name[..])?.into_bytes_with_nul();
+    let c_passwd = CString::new(&entry.password[..])?.into_bytes_with_nul();
+
+    if buflen < c_name.len() + c_passwd.len() {
+        return Err(BufferFillError::InsufficientBuffer);
+    }
+
+    let mut buf_cur = buffer;
+    unsafe {
+        (*sp).sp_lstchg = entry.last_change as c_long;
+        (*sp).sp_min = -1;
+        (*sp).sp_max = -1;
+        (*sp).sp_warn = -1;
+        (*sp).sp_inact = -1;
+        (*sp).sp_expire = entry.expire as c_long;
+        (*sp).sp_flag = !0;
+
+        copy_nonoverlapping(c_name.as_ptr(), buf_cur as *mut u8, c_name.len());
+        (*sp).sp_namp = buf_cur;
+        buf_cur = buf_cur.add(c_name.len());
+
+        copy_nonoverlapping(c_passwd.as_ptr(), buf_cur as *mut u8, c_passwd.len());
+        (*sp).sp_pwdp = buf_cur;
+    }
+
+    Ok(())
+}
+
+/// The entries being enumerated by getpwent/getgrent, and where we are in them
+///
+/// The whole database is fetched from the daemon by setpwent/setgrent, and handed out one
+/// entry at a time.
+struct Enumeration<T> {
+    entries: Vec<T>,
+    next: usize,
+}
+
+lazy_static! {
+    static ref PASSWD_ENUMERATION: Mutex<Option<Enumeration<PasswdEntry>>> = Mutex::new(None);
+    static ref GROUP_ENUMERATION: Mutex<Option<Enumeration<GroupEntry>>> = Mutex::new(None);
+}
+
+/// setpwent starts (or restarts) an enumeration of every user in the directory.
+///
+/// Enumeration must be enabled in the daemon's configuration, because listing every user is
+/// expensive on large tenants; otherwise the enumeration is empty.
+#[no_mangle]
+pub extern "C" fn _nss_aad_setpwent(_stayopen: c_int) -> i32 {
+    #[cfg(debug_assertions)]
+    println!("libnss-aad setpwent called");
+
+    let mut enumeration = match PASSWD_ENUMERATION.lock() {
+        Ok(e) => e,
+        Err(_) => return NssStatus::Unavailable as i32,
+    };
+    match client::query(&Request::EnumeratePasswd) {
+        Response::PasswdList(entries) => {
+            *enumeration = Some(Enumeration {
+                                    entries,
+                                    next: 0,
+                                });
+            NssStatus::Success as i32
+        }
+        Response::Unavailable => NssStatus::Unavailable as i32,
+        _ => NssStatus::TryAgain as i32,
+    }
+}
+
+/// getpwent returns the next user in the enumeration started by setpwent.
+///
+/// If the buffer is too small, the same user is returned by the next call. Users whose entries
+/// cannot be represented (because of a NUL in a field) are left out.
+#[no_mangle]
+pub extern "C" fn _nss_aad_getpwent_r(pw: *mut passwd,
+                                      buffer: *mut c_char,
+                                      buflen: size_t,
+                                      errnop: *mut i32)
+                                      -> i32 {
+
+    assert!(!pw.is_null() && !buffer.is_null() && !errnop.is_null());
+
+    if PASSWD_ENUMERATION.lock().map(|e| e.is_none()).unwrap_or(false) {
+        let status = _nss_aad_setpwent(0);
+        if status != NssStatus::Success as i32 {
+            unsafe { *errnop = EAGAIN };
+            return status;
+        }
+    }
+    let mut enumeration = match PASSWD_ENUMERATION.lock() {
+        Ok(e) => e,
+        Err(_) => return nss_out_of_service(errnop),
+    };
+    let enumeration = match *enumeration {
+        Some(ref mut e) => e,
+        None => return nss_entry_not_available(errnop),
+    };
+    while let Some(entry) = enumeration.entries.get(enumeration.next) {
+        match fill_passwd_buf(pw, buffer, buflen, entry) {
+            Ok(()) => {
+                enumeration.next += 1;
+                return NssStatus::Success as i32;
+            }
+            // NotFound would end the enumeration, so a user that cannot be represented is skipped
+            Err(BufferFillError::ZeroByteInString) => enumeration.next += 1,
+            Err(_) => return nss_insufficient_buffer(errnop),
+        }
+    }
+    nss_entry_not_available(errnop)
+}
+
+/// endpwent ends the enumeration started by setpwent.
+#[no_mangle]
+pub extern "C" fn _nss_aad_endpwent() -> i32 {
+    if let Ok(mut enumeration) = PASSWD_ENUMERATION.lock() {
+        *enumeration = None;
+    }
+    NssStatus::Success as i32
+}
+
+/// setgrent starts (or restarts) an enumeration of every group in the directory.
+///
+/// As with setpwent, enumeration must be enabled in the daemon's configuration.
+#[no_mangle]
+pub extern "C" fn _nss_aad_setgrent(_stayopen: c_int) -> i32 {
+    #[cfg(debug_assertions)]
+    println!("libnss-aad setgrent called");
+
+    let mut enumeration = match GROUP_ENUMERATION.lock() {
+        Ok(e) => e,
+        Err(_) => return NssStatus::Unavailable as i32,
+    };
+    match client::query(&Request::EnumerateGroup) {
+        Response::GroupList(entries) => {
+            *enumeration = Some(Enumeration {
+                                    entries,
+                                    next: 0,
+                                });
+            NssStatus::Success as i32
+        }
+        Response::Unavailable => NssStatus::Unavailable as i32,
+        _ => NssStatus::TryAgain as i32,
+    }
+}
+
+/// getgrent returns the next group in the enumeration started by setgrent.
+///
+/// If the buffer is too small, the same group is returned by the next call. Groups whose
+/// entries cannot be represented (because of a NUL in a field) are left out.
+#[no_mangle]
+pub extern "C" fn _nss_aad_getgrent_r(result: *mut group,
+                                      buffer: *mut c_char,
+                                      buflen: size_t,
+                                      errnop: *mut i32)
+                                      -> i32 {
+
+    assert!(!result.is_null() && !buffer.is_null() && !errnop.is_null());
+
+    if GROUP_ENUMERATION.lock().map(|e| e.is_none()).unwrap_or(false) {
+        let status = _nss_aad_setgrent(0);
+        if status != NssStatus::Success as i32 {
+            unsafe { *errnop = EAGAIN };
+            return status;
+        }
+    }
+    let mut enumeration = match GROUP_ENUMERATION.lock() {
+        Ok(e) => e,
+        Err(_) => return nss_out_of_service(errnop),
+    };
+    let enumeration = match *enumeration {
+        Some(ref mut e) => e,
+        None => return nss_entry_not_available(errnop),
+    };
+    while let Some(entry) = enumeration.entries.get(enumeration.next) {
+        match fill_group_buf(result, buffer, buflen, entry) {
+            Ok(()) => {
+                enumeration.next += 1;
+                return NssStatus::Success as i32;
+            }
+            // NotFound would end the enumeration, so a group that cannot be represented is
+            // skipped
+            Err(BufferFillError::ZeroByteInString) => enumeration.next += 1,
+            Err(BufferFillError::InsufficientBuffer) => return nss_insufficient_buffer(errnop),
+            Err(_) => return nss_entry_not_available(errnop),
+        }
+    }
+    nss_entry_not_available(errnop)
+}
+
+/// endgrent ends the enumeration started by setgrent.
+#[no_mangle]
+pub extern "C" fn _nss_aad_endgrent() -> i32 {
+    if let Ok(mut enumeration) = GROUP_ENUMERATION.lock() {
+        *enumeration = None;
+    }
+    NssStatus::Success as i32
+}
+
 /// One of the functions used ran temporarily out of resources or a service is currently not
 /// available.
 fn nss_out_of_service(errnop: *mut i32) -> i32 {
@@ -658,8 +880,8 @@ fn nss_insufficient_buffer(errnop: *mut i32) -> i32 {
     NssStatus::TryAgain as i32
 }
 
-/// A necessary input file cannot be found.
-fn nss_input_file_err(errnop: *mut i32) -> i32 {
+/// The service (nss-aad-daemon) cannot be reached.
+fn nss_unavailable(errnop: *mut i32) -> i32 {
     unsafe { *errnop = ENOENT };
     NssStatus::Unavailable as i32
 }
@@ -669,3 +891,12 @@ fn nss_entry_not_available(errnop: *mut i32) -> i32 {
     unsafe { *errnop = ENOENT };
     NssStatus::NotFound as i32
 }
+
+/// Translate an unsuccessful Response from the daemon into an NSS status.
+fn nss_lookup_failed(response: Response, errnop: *mut i32) -> i32 {
+    match response {
+        Response::NotFound => nss_entry_not_available(errnop),
+        Response::Unavailable => nss_unavailable(errnop),
+        _ => nss_out_of_service(errnop),
+    }
+}
@@ -0,0 +1,93 @@
+//! Local groups, like `docker` or `wheel`, granted by membership of directory groups
+//!
+//! The `local_groups` section of the configuration file maps the object IDs of directory groups
+//! onto the names of groups defined in `/etc/group`. Members of a directory group are given the
+//! local group's GID by `initgroups`, so that the local group does not need to list them.
+//! Directory groups are not matched by display name, which anyone who can create a group could
+//! copy.
+//!
+//! `/etc/group` is read directly, rather than through `getgrnam`, which could ask this very
+//! plugin about the group.
+//!
+//! The plugin deliberately does not find the local groups by name at all (see `is_local`), even
+//! where a directory group has the same name: `files` is the only source of their entries.
+
+use AadConfig;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+
+const GROUP_FILE: &str = "/etc/group";
+
+/// Whether `name` is one of the local groups that directory groups are mapped onto, which this
+/// plugin intentionally reports as not found, leaving them to `files`.
+pub fn is_local(config: &AadConfig, name: &str) -> bool {
+    config.local_groups.values().any(|local| local == name)
+}
+
+/// The GIDs of the local groups that membership of `groups` (the object IDs of directory
+/// groups) grants, in the order of `/etc/group`.
+///
+/// Local groups that are not in `/etc/group` are skipped.
+pub fn granted(config: &AadConfig, groups: &[String]) -> Vec<u32> {
+    let locals: HashSet<&str> = config
+        .local_groups
+        .iter()
+        .filter(|&(group, _)| groups.iter().any(|g| g.eq_ignore_ascii_case(group)))
+        .map(|(_, local)| &local[..])
+        .collect();
+    if locals.is_empty() {
+        return vec![];
+    }
+
+    let mut contents = String::new();
+    if let Err(_e) = File::open(GROUP_FILE).and_then(|mut f| f.read_to_string(&mut contents)) {
+        #[cfg(debug_assertions)]
+        println!("libnss-aad could not read {}: {:?}", GROUP_FILE, _e);
+        return vec![];
+    }
+    let gids = local_gids(&contents, &locals);
+    #[cfg(debug_assertions)]
+    {
+        if gids.len() < locals.len() {
+            println!("libnss-aad some of the local groups {:?} are not in {}", locals, GROUP_FILE);
+        }
+    }
+    gids
+}
+
+/// The GIDs of the groups called `names` in `contents`, the contents of `/etc/group`, in the
+/// order they appear there.
+fn local_gids(contents: &str, names: &HashSet<&str>) -> Vec<u32> {
+    contents
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .filter(|fields| fields.len() >= 3 && names.contains(fields[0]))
+        .filter_map(|fields| fields[2].parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::local_gids;
+
+    const GROUP: &str = "root:x:0:\n\
+                         adm:x:4:syslog\n\
+                         docker:x:999:\n\
+                         broken\n\
+                         audio:x:29:pulse\n\
+                         wheel:x:notanumber:\n";
+
+    #[test]
+    fn gids_come_in_group_file_order() {
+        let names: HashSet<&str> = ["docker", "audio", "adm"].iter().cloned().collect();
+        assert_eq!(local_gids(GROUP, &names), vec![4, 999, 29]);
+    }
+
+    #[test]
+    fn missing_and_malformed_groups_are_skipped() {
+        let names: HashSet<&str> = ["wheel", "broken", "sudo", "audio"].iter().cloned().collect();
+        assert_eq!(local_gids(GROUP, &names), vec![29]);
+    }
+}
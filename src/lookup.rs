@@ -0,0 +1,549 @@
+//! The daemon's answers to each kind of Request
+//!
+//! These functions hold the logic that turns directory information into passwd and group
+//! entries. They are only ever run inside nss-aad-daemon.
+
+extern crate hyper;
+
+use AadConfig;
+use GroupInfo;
+use UserInfo;
+use azure;
+use idmap;
+use local_groups;
+use members;
+use names;
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use negative_cache::NegativeCache;
+use offline_cache::OfflineCache;
+use templates;
+use self::hyper::status::StatusCode;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use protocol::{GroupEntry, PasswdEntry, Request, Response, ShadowEntry};
+
+/// The daemon's view of the directory: its configuration, and whatever it remembers of earlier
+/// answers.
+pub struct Directory {
+    config: AadConfig,
+    offline_cache: Option<OfflineCache>,
+    negative_cache: Option<NegativeCache>,
+    passwd_listing: Listing,
+    group_listing: Listing,
+}
+
+/// How long an enumeration is used for before the directory is listed again. Users and groups
+/// created or changed in the meantime are not enumerated until then.
+const ENUMERATION_INTERVAL_SECS: u64 = 300;
+
+/// The last answer to one kind of enumeration request.
+///
+/// Every `getent passwd` (and every other program that calls setpwent) would otherwise list the
+/// whole tenant, which takes minutes on the large tenants that enumeration is most wanted on,
+/// so an answer is kept for ENUMERATION_INTERVAL_SECS.
+struct Listing {
+    listing: Mutex<Option<(Instant, Response)>>,
+}
+
+impl Listing {
+    fn new() -> Listing {
+        Listing { listing: Mutex::new(None) }
+    }
+
+    /// The current listing, or a new one made by `list` if the current one is too old.
+    ///
+    /// Only one listing is made at a time; concurrent requests wait for it rather than making
+    /// their own. Failures are not kept.
+    fn get<F>(&self, list: F) -> GraphInfoResult<Response>
+        where F: FnOnce() -> GraphInfoResult<Response>
+    {
+        let mut listing = self.listing.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((listed, ref response)) = *listing {
+            if listed.elapsed() < Duration::from_secs(ENUMERATION_INTERVAL_SECS) {
+                return Ok(response.clone());
+            }
+        }
+        let response = list()?;
+        *listing = Some((Instant::now(), response.clone()));
+        Ok(response)
+    }
+}
+
+impl Directory {
+    pub fn new(config: AadConfig) -> Directory {
+        let offline_cache = config.offline_cache.as_ref().map(OfflineCache::open);
+        let negative_cache = match config.negative_cache_ttl {
+            0 => None,
+            ttl => Some(NegativeCache::new(ttl)),
+        };
+        Directory {
+            config,
+            offline_cache,
+            negative_cache,
+            passwd_listing: Listing::new(),
+            group_listing: Listing::new(),
+        }
+    }
+
+    /// Answer a single request from the plugin.
+    ///
+    /// Successful answers are remembered in the offline cache (if there is one), and forgotten
+    /// again if the directory later says they do not exist. If AAD cannot be reached, a
+    /// remembered answer is given instead, provided it is not too stale.
+    ///
+    /// NotFound answers are remembered in the negative cache (if there is one), and repeated
+    /// without asking AAD until they expire.
+    pub fn handle(&self, request: &Request) -> Response {
+        self.handle_with(request, |request| self.query(request))
+    }
+
+    /// As `handle`, but asking `query` rather than the directory.
+    fn handle_with<F>(&self, request: &Request, query: F) -> Response
+        where F: FnOnce(&Request) -> GraphInfoResult<Response>
+    {
+        if let Some(ref cache) = self.negative_cache {
+            if cache.contains(request) {
+                return Response::NotFound;
+            }
+        }
+
+        match query(request) {
+            Ok(response) => {
+                if let Some(ref cache) = self.offline_cache {
+                    cache.store(request, &response);
+                }
+                response
+            }
+            Err(e) => {
+                if e.is_not_found() {
+                    if let Some(ref cache) = self.offline_cache {
+                        cache.forget(request);
+                    }
+                }
+                let cached = match self.offline_cache {
+                    Some(ref cache) if e.is_outage() => cache.fetch(request),
+                    _ => None,
+                };
+                if let Some(response) = cached {
+                    #[cfg(debug_assertions)]
+                    println!("libnss-aad answering {:?} from the offline cache", request);
+                    return response;
+                }
+                let response = lookup_failed(e);
+                if let Some(ref cache) = self.negative_cache {
+                    cache.store(request, &response);
+                }
+                response
+            }
+        }
+    }
+
+    /// Answer `request` from the directory.
+    fn query(&self, request: &Request) -> GraphInfoResult<Response> {
+        let config = &self.config;
+        match *request {
+            Request::GetPwNam(ref name) => getpwnam(config, name),
+            Request::GetPwUid(uid) => getpwuid(config, uid),
+            Request::GetGrNam(ref name) => getgrnam(config, name),
+            Request::GetGrGid(gid) => getgrgid(config, gid),
+            Request::GetSpNam(ref name) => getspnam(config, name),
+            Request::InitGroups(ref name) => initgroups(config, name),
+            Request::EnumeratePasswd => self.passwd_listing.get(|| enumerate_passwd(config)),
+            Request::EnumerateGroup => self.group_listing.get(|| enumerate_group(config)),
+        }
+    }
+}
+
+/// Translate a failed directory lookup into a Response.
+///
+/// Lookups that positively did not find anything are NotFound; anything else might succeed if
+/// tried again later.
+fn lookup_failed(err: GraphInfoRetrievalError) -> Response {
+    #[cfg(debug_assertions)]
+    println!("libnss-aad lookup failed: {:?}", err);
+    match err {
+        GraphInfoRetrievalError::BadHTTPResponse { status: StatusCode::NotFound, .. } |
+        GraphInfoRetrievalError::TooManyResults |
+        GraphInfoRetrievalError::NotFound => Response::NotFound,
+        _ => Response::TryAgain,
+    }
+}
+
+/// Build the passwd entry for a directory user, from their POSIX attributes where they have
+/// them, and otherwise from the templates (see `templates`).
+///
+/// The password field is `x`, which sends `pam_unix` to the shadow entry, only if `shadow` is
+/// enabled; otherwise it is `.`, which OpenSSH does not treat as locked, unlike `*`.
+///
+/// If there are any group overrides or primary groups, this asks the directory for the user's
+/// groups.
+fn passwd_entry(config: &AadConfig, userinfo: UserInfo) -> GraphInfoResult<PasswdEntry> {
+    let memberships = if needs_memberships(config) {
+        azure::get_user_memberships(config, &userinfo.object_id)?
+    } else {
+        no_memberships()
+    };
+    Ok(passwd_entry_with(config, userinfo, &memberships))
+}
+
+/// Whether passwd entries depend on the groups that users belong to.
+fn needs_memberships(config: &AadConfig) -> bool {
+    !config.group_overrides.is_empty() || !config.primary_groups.is_empty()
+}
+
+fn no_memberships() -> azure::Memberships {
+    azure::Memberships {
+        object_ids: vec![],
+        groups: vec![],
+    }
+}
+
+/// As `passwd_entry`, for a user who belongs to `memberships`.
+fn passwd_entry_with(config: &AadConfig,
+                     userinfo: UserInfo,
+                     memberships: &azure::Memberships)
+                     -> PasswdEntry {
+    let fields = templates::fields(config, &userinfo, &memberships.object_ids);
+    let gid = primary_gid(config, &userinfo, &memberships.groups);
+    PasswdEntry {
+        name: userinfo.username,
+        passwd: if config.shadow { "x" } else { "." }.to_string(),
+        uid: userinfo.userid,
+        gid,
+        gecos: fields.gecos,
+        dir: fields.home,
+        shell: fields.shell,
+    }
+}
+
+/// The user's primary GID: their gidNumber, if they have one, or else that of the first of the
+/// `primary_groups` among `groups` (the groups they belong to), or else their private group, if
+/// enabled, or else the default.
+fn primary_gid(config: &AadConfig, userinfo: &UserInfo, groups: &[GroupInfo]) -> u32 {
+    if let Some(gid) = userinfo.groupid {
+        return gid;
+    }
+    let primary_group = config
+        .primary_groups
+        .iter()
+        .filter_map(|id| groups.iter().find(|g| g.object_id.eq_ignore_ascii_case(id)))
+        .next();
+    match primary_group {
+        Some(group) => group.group_id,
+        None if config.user_private_groups => userinfo.userid,
+        None => config.default_user_group_id,
+    }
+}
+
+fn getpwnam(config: &AadConfig, name: &str) -> GraphInfoResult<Response> {
+    idmap::allocating(config, || {
+        let userinfo = names::resolve(config, name)?;
+        Ok(Response::Passwd(passwd_entry(config, userinfo)?))
+    })
+}
+
+fn getpwuid(config: &AadConfig, uid: u32) -> GraphInfoResult<Response> {
+    let userinfo = azure::get_user_info_by_id(config, uid)?;
+    Ok(Response::Passwd(passwd_entry(config, userinfo)?))
+}
+
+/// Build the shadow entry for a directory user.
+///
+/// AAD passwords are never available to us, so the password field is only `.` (which OpenSSH
+/// does not treat as locked, unlike `*`) or, for a disabled account, `!`. A disabled account is
+/// also expired, so that sshd and PAM refuse it even for logins that do not use a password at
+/// all, such as SSH keys or certificates.
+fn shadow_entry(userinfo: UserInfo) -> ShadowEntry {
+    let (password, expire) = if userinfo.enabled {
+        (".", userinfo.expires.unwrap_or(-1))
+    } else {
+        ("!", 1)
+    };
+    ShadowEntry {
+        name: userinfo.username,
+        password: password.to_string(),
+        last_change: userinfo.password_changed.unwrap_or(-1),
+        expire,
+    }
+}
+
+fn getspnam(config: &AadConfig, name: &str) -> GraphInfoResult<Response> {
+    let userinfo = idmap::allocating(config, || names::resolve(config, name))?;
+    Ok(Response::Shadow(shadow_entry(userinfo)))
+}
+
+/// Build the group entry for a directory group, looking up its members by the group's object
+/// ID. A failure to list the members fails the lookup, rather than giving (and caching) a group
+/// that seems to have no members.
+fn group_entry(config: &AadConfig,
+               name: &str,
+               gid: u32,
+               object_id: &str)
+               -> GraphInfoResult<GroupEntry> {
+    Ok(GroupEntry {
+           name: name.to_string(),
+           gid,
+           members: members::resolve(config, object_id)?,
+       })
+}
+
+/// The user private group of a directory user: named after them, with their UID as its GID,
+/// and with them as its only member.
+fn private_group(userinfo: &UserInfo) -> GroupEntry {
+    GroupEntry {
+        name: userinfo.username.clone(),
+        gid: userinfo.userid,
+        members: vec![userinfo.username.clone()],
+    }
+}
+
+/// If user private groups are enabled, the private group of the user found by `find`.
+///
+/// Only a failure to reach the directory is an error; failing to find exactly one user for
+/// any other reason just means that there is no private group.
+fn find_private_group<F>(config: &AadConfig, find: F) -> GraphInfoResult<Option<GroupEntry>>
+    where F: FnOnce() -> GraphInfoResult<UserInfo>
+{
+    if !config.user_private_groups {
+        return Ok(None);
+    }
+    match find() {
+        Ok(userinfo) => Ok(Some(private_group(&userinfo))),
+        Err(ref e) if !e.is_outage() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A user's private group, if there is one, takes precedence over a directory group of the
+/// same name. It is only found by the exact name the user goes by.
+///
+/// Local groups that directory groups are mapped onto (see `local_groups`) are deliberately
+/// not found, even if there is a directory group of the same name, so that they are always
+/// answered by `files`.
+fn getgrnam(config: &AadConfig, name: &str) -> GraphInfoResult<Response> {
+    if local_groups::is_local(config, name) {
+        return Err(GraphInfoRetrievalError::NotFound);
+    }
+    let private = find_private_group(config, || {
+        idmap::allocating(config, || names::find_by_name(config, name))
+    })?;
+    if let Some(group) = private {
+        return Ok(Response::Group(group));
+    }
+    let groupinfo = idmap::allocating(config, || names::resolve_group(config, name))?;
+    Ok(Response::Group(group_entry(config,
+                                   &groupinfo.groupname,
+                                   groupinfo.group_id,
+                                   &groupinfo.object_id)?))
+}
+
+/// A user's private group, if there is one, takes precedence over a directory group with the
+/// same ID.
+fn getgrgid(config: &AadConfig, gid: u32) -> GraphInfoResult<Response> {
+    if let Some(group) = find_private_group(config, || azure::get_user_info_by_id(config, gid))? {
+        return Ok(Response::Group(group));
+    }
+    let groupinfo = azure::get_group_info_by_id(config, gid)?;
+    Ok(Response::Group(group_entry(config, &groupinfo.groupname, gid, &groupinfo.object_id)?))
+}
+
+/// The GIDs of the user's groups, and of the local groups that those groups are mapped onto. A
+/// user in any groups at all is also in their primary group.
+fn initgroups(config: &AadConfig, name: &str) -> GraphInfoResult<Response> {
+    let (userinfo, memberships) = idmap::allocating(config, || {
+        let userinfo = names::resolve(config, name)?;
+        let memberships = azure::get_user_memberships(config, &userinfo.object_id)?;
+        Ok((userinfo, memberships))
+    })?;
+    let mut user_groups: Vec<u32> = memberships.groups.iter().map(|g| g.group_id).collect();
+    for gid in local_groups::granted(config, &memberships.object_ids) {
+        if !user_groups.contains(&gid) {
+            user_groups.push(gid);
+        }
+    }
+    if !user_groups.is_empty() {
+        user_groups.push(primary_gid(config, &userinfo, &memberships.groups));
+    }
+    Ok(Response::Groups(user_groups))
+}
+
+/// Every user in the directory, if enumeration is enabled. Otherwise, nobody.
+///
+/// If passwd entries depend on users' groups, every group is listed once, rather than asking
+/// for each user's groups in turn.
+fn enumerate_passwd(config: &AadConfig) -> GraphInfoResult<Response> {
+    if !config.enumerate {
+        return Ok(Response::PasswdList(vec![]));
+    }
+    let users = azure::list_users(config)?;
+    let mut memberships = if needs_memberships(config) {
+        members::memberships(config, &azure::list_groups_with_members(config)?)
+    } else {
+        HashMap::new()
+    };
+    let entries = users
+        .into_iter()
+        .map(|u| {
+                 let m = memberships.remove(&u.object_id).unwrap_or_else(no_memberships);
+                 passwd_entry_with(config, u, &m)
+             })
+        .collect();
+    Ok(Response::PasswdList(entries))
+}
+
+/// Every group in the directory, with its members, if enumeration is enabled. Otherwise, no
+/// groups.
+///
+/// Every group is listed with its members at once, rather than asking for each group's members
+/// in turn.
+fn enumerate_group(config: &AadConfig) -> GraphInfoResult<Response> {
+    if !config.enumerate {
+        return Ok(Response::GroupList(vec![]));
+    }
+    let listing = azure::list_groups_with_members(config)?;
+    let listed: HashMap<&str, &azure::GroupMembers> = listing
+        .iter()
+        .map(|g| (&g.object_id[..], &g.members))
+        .collect();
+    let fetch = |group: &str| match listed.get(group) {
+        Some(members) => Ok((*members).clone()),
+        None => azure::get_group_members(config, group),
+    };
+    let mut entries = vec![];
+    for group in &listing {
+        if let Some(ref info) = group.info {
+            entries.push(GroupEntry {
+                             name: info.groupname.clone(),
+                             gid: info.group_id,
+                             members: members::resolve_with(config, &group.object_id, fetch)?,
+                         });
+        }
+    }
+    if config.user_private_groups {
+        entries.extend(azure::list_users(config)?.iter().map(private_group));
+    }
+    Ok(Response::GroupList(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use {AadConfig, GroupInfo, UserInfo};
+    use error::GraphInfoRetrievalError;
+    use protocol::{PasswdEntry, Request, Response, ShadowEntry};
+    use serde_yaml;
+    use std::cell::Cell;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process;
+    use super::hyper;
+    use super::{Directory, Listing, primary_gid, shadow_entry};
+
+    fn user(enabled: bool, password_changed: Option<i64>, expires: Option<i64>) -> UserInfo {
+        let mut user = UserInfo::test_user("alice", 1000);
+        user.enabled = enabled;
+        user.password_changed = password_changed;
+        user.expires = expires;
+        user
+    }
+
+    #[test]
+    fn primary_group_is_chosen_by_object_id() {
+        let config: AadConfig = serde_yaml::from_str("tenant: contoso.com\n\
+                                                      default_user_group_id: 100\n\
+                                                      primary_groups:\n\
+                                                      - 5e7a2c91-3b6d-4f08-9a1e-c4d2b8f06e53\n")
+                .unwrap();
+        let group = |object_id: &str, group_id| {
+            GroupInfo {
+                groupname: "data-science".to_string(),
+                object_id: object_id.to_string(),
+                domain: None,
+                group_id,
+            }
+        };
+        let alice = UserInfo::test_user("alice", 1000);
+        let impostor = || group("0d9b4f27-e6a1-4c53-b872-19f5a3c8d64e", 2000);
+        assert_eq!(primary_gid(&config, &alice, &[impostor()]), 100);
+        let genuine = group("5E7A2C91-3B6D-4F08-9A1E-C4D2B8F06E53", 3000);
+        assert_eq!(primary_gid(&config, &alice, &[impostor(), genuine]), 3000);
+    }
+
+    #[test]
+    fn enabled_user_keeps_dates() {
+        assert_eq!(shadow_entry(user(true, Some(17000), Some(18000))),
+                   ShadowEntry {
+                       name: "alice".to_string(),
+                       password: ".".to_string(),
+                       last_change: 17000,
+                       expire: 18000,
+                   });
+    }
+
+    #[test]
+    fn unknown_dates_are_minus_one() {
+        let entry = shadow_entry(user(true, None, None));
+        assert_eq!(entry.last_change, -1);
+        assert_eq!(entry.expire, -1);
+    }
+
+    #[test]
+    fn disabled_user_is_locked_and_expired() {
+        let entry = shadow_entry(user(false, Some(17000), None));
+        assert_eq!(entry.password, "!");
+        assert_eq!(entry.last_change, 17000);
+        assert_eq!(entry.expire, 1);
+    }
+
+    #[test]
+    fn enumeration_is_listed_once_per_interval() {
+        let listing = Listing::new();
+        let listings = Cell::new(0);
+        let list = || {
+            listings.set(listings.get() + 1);
+            Ok(Response::Groups(vec![listings.get()]))
+        };
+        assert_eq!(listing.get(list).unwrap(), Response::Groups(vec![1]));
+        assert_eq!(listing.get(list).unwrap(), Response::Groups(vec![1]));
+        assert_eq!(listings.get(), 1);
+    }
+
+    #[test]
+    fn failed_enumeration_is_not_kept() {
+        let listing = Listing::new();
+        assert!(listing.get(|| Err(GraphInfoRetrievalError::NotFound)).is_err());
+        assert_eq!(listing.get(|| Ok(Response::Groups(vec![]))).unwrap(),
+                   Response::Groups(vec![]));
+    }
+
+    #[test]
+    fn timed_out_lookup_is_answered_from_the_offline_cache() {
+        let file = env::temp_dir().join(format!("nss-aad-lookup-test.{}", process::id()));
+        let config = serde_yaml::from_str(&format!("tenant: contoso.com\n\
+                                                    default_user_group_id: 1000\n\
+                                                    offline_cache: {{file: {:?}}}\n",
+                                                   file))
+                .unwrap();
+        let directory = Directory::new(config);
+        let request = Request::GetPwUid(1001);
+        let answer = Response::Passwd(PasswdEntry {
+                                          name: "alice".to_string(),
+                                          passwd: ".".to_string(),
+                                          uid: 1001,
+                                          gid: 1000,
+                                          gecos: "Alice".to_string(),
+                                          dir: "/home/alice".to_string(),
+                                          shell: "/bin/bash".to_string(),
+                                      });
+        let timed_out = || {
+            let timeout = io::Error::new(io::ErrorKind::TimedOut, "connection timed out");
+            Err(GraphInfoRetrievalError::HTTPError(hyper::Error::Io(timeout)))
+        };
+
+        assert_eq!(directory.handle_with(&request, |_| timed_out()), Response::TryAgain);
+        assert_eq!(directory.handle_with(&request, |_| Ok(answer.clone())), answer);
+        assert_eq!(directory.handle_with(&request, |_| timed_out()), answer);
+        let _ = fs::remove_file(&file);
+    }
+}
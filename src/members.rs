@@ -0,0 +1,245 @@
+//! Resolution of a group's members, for gr_mem
+//!
+//! A group's members are fetched a page at a time until every page has been read. If nested
+//! membership is enabled, the members of groups within the group are included too, down to a
+//! configured depth; a group is only ever expanded once, so cycles of nested groups end.
+//!
+//! When every group is listed at once, for enumeration, the listing also says which groups each
+//! user belongs to, so that they need not be asked for one user at a time.
+
+use AadConfig;
+use azure::{self, GroupMembers, ListedGroup, Memberships};
+use error::GraphInfoResult;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Nested groups are expanded at most this many levels deep, unless configured otherwise.
+const DEFAULT_MAX_DEPTH: u32 = 5;
+
+/// The `group_members` section of the configuration file.
+#[derive(Deserialize,Debug)]
+pub struct GroupMembersConfig {
+    #[serde(default)]
+    nested: bool,
+    #[serde(default = "default_max_depth")]
+    max_depth: u32,
+    /// Groups with more members than this are given no members at all; 0 means no limit.
+    #[serde(default)]
+    max_members: usize,
+}
+
+fn default_max_depth() -> u32 {
+    DEFAULT_MAX_DEPTH
+}
+
+impl Default for GroupMembersConfig {
+    fn default() -> GroupMembersConfig {
+        GroupMembersConfig {
+            nested: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_members: 0,
+        }
+    }
+}
+
+/// Return the names of the members of the group identified by `object_id`.
+///
+/// If the group has more members than `max_members`, the list is empty: an empty gr_mem is
+/// plainly incomplete, where a truncated one would be silently wrong.
+///
+/// A nested group that has gone missing has no members. Any other failure to list members is
+/// an error, rather than a shorter list.
+pub fn resolve(config: &AadConfig, object_id: &str) -> GraphInfoResult<Vec<String>> {
+    resolve_with(config, object_id, |group| azure::get_group_members(config, group))
+}
+
+/// As `resolve`, but getting the direct members of each group from `fetch`.
+pub fn resolve_with<F>(config: &AadConfig,
+                       object_id: &str,
+                       fetch: F)
+                       -> GraphInfoResult<Vec<String>>
+    where F: Fn(&str) -> GraphInfoResult<GroupMembers>
+{
+    let settings = &config.group_members;
+    let mut names = vec![];
+    let mut seen_names = HashSet::new();
+    let mut seen_groups = HashSet::new();
+    let mut pending = VecDeque::new();
+
+    seen_groups.insert(object_id.to_string());
+    pending.push_back((object_id.to_string(), 0));
+    while let Some((group, depth)) = pending.pop_front() {
+        let members = match fetch(&group) {
+            Ok(members) => members,
+            Err(ref e) if depth > 0 && e.is_not_found() => continue,
+            Err(e) => return Err(e),
+        };
+        for user in members.users {
+            if seen_names.insert(user.username.clone()) {
+                names.push(user.username);
+            }
+        }
+        if settings.max_members > 0 && names.len() > settings.max_members {
+            #[cfg(debug_assertions)]
+            println!("libnss-aad group {} has more than {} members; listing none",
+                     object_id,
+                     settings.max_members);
+            return Ok(vec![]);
+        }
+        if !settings.nested {
+            continue;
+        }
+        for nested in members.groups {
+            if depth >= settings.max_depth {
+                #[cfg(debug_assertions)]
+                println!("libnss-aad not expanding group {} beyond depth {}",
+                         nested,
+                         settings.max_depth);
+                continue;
+            }
+            if seen_groups.insert(nested.clone()) {
+                pending.push_back((nested, depth + 1));
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// The groups to which each user in `listing` (a listing of every group) belongs, keyed by the
+/// user's object ID, as get_user_memberships would find them.
+pub fn memberships(config: &AadConfig, listing: &[ListedGroup]) -> HashMap<String, Memberships> {
+    // The groups that each user or group is directly a member of
+    let mut parents: HashMap<&str, Vec<&ListedGroup>> = HashMap::new();
+    for group in listing {
+        for user in &group.members.users {
+            parents.entry(&user.object_id).or_default().push(group);
+        }
+        for nested in &group.members.groups {
+            parents.entry(nested).or_default().push(group);
+        }
+    }
+
+    let mut memberships = HashMap::new();
+    for user in listing.iter().flat_map(|g| g.members.users.iter()) {
+        if memberships.contains_key(&user.object_id) {
+            continue;
+        }
+        let mut found = Memberships {
+            object_ids: vec![],
+            groups: vec![],
+        };
+        let mut seen = HashSet::new();
+        let mut pending: VecDeque<&str> = VecDeque::new();
+        pending.push_back(&user.object_id);
+        while let Some(member) = pending.pop_front() {
+            for group in parents.get(member).map_or(&[][..], |p| &p[..]) {
+                if !seen.insert(&group.object_id[..]) {
+                    continue;
+                }
+                found.object_ids.push(group.object_id.clone());
+                found.groups.extend(group.info.iter().cloned());
+                if config.transitive_groups {
+                    pending.push_back(&group.object_id);
+                }
+            }
+        }
+        memberships.insert(user.object_id.clone(), found);
+    }
+    memberships
+}
+
+#[cfg(test)]
+mod tests {
+    use {AadConfig, UserInfo};
+    use azure::GroupMembers;
+    use error::{GraphInfoResult, GraphInfoRetrievalError};
+    use serde_yaml;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use super::resolve_with;
+
+    fn config(group_members: &str) -> AadConfig {
+        serde_yaml::from_str(&format!("tenant: contoso.com\n\
+                                       default_user_group_id: 1000\n\
+                                       group_members: {}\n",
+                                      group_members))
+                .unwrap()
+    }
+
+    fn group(users: &[&str], groups: &[&str]) -> GroupMembers {
+        GroupMembers {
+            users: users
+                .iter()
+                .enumerate()
+                .map(|(i, u)| UserInfo::test_user(u, 1000 + i as u32))
+                .collect(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    /// Resolve the members of group `a` among `groups`, returning them along with the groups
+    /// that were fetched, in order.
+    fn resolve(config: &AadConfig,
+               groups: &[(&str, GroupMembers)])
+               -> (GraphInfoResult<Vec<String>>, Vec<String>) {
+        let groups: HashMap<&str, &GroupMembers> =
+            groups.iter().map(|&(g, ref m)| (g, m)).collect();
+        let fetched = RefCell::new(vec![]);
+        let members = resolve_with(config, "a", |g| {
+            fetched.borrow_mut().push(g.to_string());
+            groups
+                .get(g)
+                .map(|m| (*m).clone())
+                .ok_or(GraphInfoRetrievalError::NotFound)
+        });
+        (members, fetched.into_inner())
+    }
+
+    #[test]
+    fn nested_groups_are_only_expanded_if_enabled() {
+        let groups = [("a", group(&["alice", "bob"], &["b"])),
+                      ("b", group(&["carol", "alice"], &[]))];
+        let (members, fetched) = resolve(&config("{}"), &groups);
+        assert_eq!(members.unwrap(), vec!["alice", "bob"]);
+        assert_eq!(fetched, vec!["a"]);
+
+        let (members, _) = resolve(&config("{nested: true}"), &groups);
+        assert_eq!(members.unwrap(), vec!["alice", "bob", "carol"]);
+    }
+
+    #[test]
+    fn nesting_stops_at_max_depth() {
+        let groups = [("a", group(&["alice"], &["b"])),
+                      ("b", group(&["bob"], &["c"])),
+                      ("c", group(&["carol"], &[]))];
+        let (members, fetched) = resolve(&config("{nested: true, max_depth: 1}"), &groups);
+        assert_eq!(members.unwrap(), vec!["alice", "bob"]);
+        assert_eq!(fetched, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn cycles_expand_each_group_once() {
+        let groups = [("a", group(&["alice"], &["b"])), ("b", group(&["bob"], &["a", "b"]))];
+        let (members, fetched) = resolve(&config("{nested: true}"), &groups);
+        assert_eq!(members.unwrap(), vec!["alice", "bob"]);
+        assert_eq!(fetched, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn too_many_members_lists_none() {
+        let groups = [("a", group(&["alice", "bob"], &["b"])), ("b", group(&["carol"], &[]))];
+        let (members, _) = resolve(&config("{nested: true, max_members: 3}"), &groups);
+        assert_eq!(members.unwrap(), vec!["alice", "bob", "carol"]);
+        let (members, _) = resolve(&config("{nested: true, max_members: 2}"), &groups);
+        assert!(members.unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_a_missing_nested_group_is_ignored() {
+        let groups = [("a", group(&["alice"], &["gone"]))];
+        let (members, _) = resolve(&config("{nested: true}"), &groups);
+        assert_eq!(members.unwrap(), vec!["alice"]);
+
+        let (members, _) = resolve(&config("{nested: true}"), &[]);
+        assert!(members.is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! The names that directory users go by on this host
+//!
+//! By default a user's name is their full userPrincipalName, like `alice@contoso.com`. The
+//! `short_names` section of the configuration file can instead give them a short name, like
+//! `alice`, which is what appears in passwd entries, group member lists and so on. A short name
+//! is qualified again (see `resolve`) before the directory is asked about it.
+//!
+//! Lookups by name also accept the other forms of a user's name that people type, such as
+//! `CONTOSO\alice` or a mail address, and resolve them to the one user they belong to.
+
+extern crate serde_json;
+
+use AadConfig;
+use GroupInfo;
+use UserInfo;
+use azure;
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use self::serde_json::Value;
+
+/// The `short_names` section of the configuration file, naming a `mode` and its parameters.
+#[derive(Deserialize,Debug)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ShortNamesConfig {
+    /// The UPN without its suffix, for UPNs ending in one of `suffixes`
+    StripSuffix { suffixes: Vec<String> },
+    /// The on-premises sAMAccountName
+    SamAccountName,
+    /// The mail nickname (the part of the mail address before the `@`, usually)
+    MailNickname,
+}
+
+/// The user property holding the short name, if it is not derived from the UPN.
+pub fn property(config: &AadConfig) -> Option<&'static str> {
+    match config.short_names {
+        Some(ShortNamesConfig::SamAccountName) => Some("onPremisesSamAccountName"),
+        Some(ShortNamesConfig::MailNickname) => Some("mailNickname"),
+        _ => None,
+    }
+}
+
+/// The name that the user with the given UPN and Graph `user` object goes by.
+///
+/// Users without a short name (with some other UPN suffix, or without the property) go by their
+/// UPN.
+pub fn present(config: &AadConfig, user: &Value, upn: &str) -> String {
+    match config.short_names {
+        Some(ShortNamesConfig::StripSuffix { ref suffixes }) => {
+            match upn.rfind('@') {
+                Some(at) if suffixes.iter().any(|s| s.eq_ignore_ascii_case(&upn[at + 1..])) => {
+                    upn[..at].to_string()
+                }
+                _ => upn.to_string(),
+            }
+        }
+        Some(_) => {
+            match property(config).and_then(|p| user[p].as_str()) {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => upn.to_string(),
+            }
+        }
+        None => upn.to_string(),
+    }
+}
+
+/// Find the user that goes by `name`, or by any of the other names people log in with.
+///
+/// As well as the names that users go by (see `present`), these are accepted:
+///
+/// * an object ID
+/// * a down-level logon name, `DOMAIN\sam`, for NetBIOS domain names in `netbios_domains`
+/// * a UPN, or failing that a mail address (`mail`, or one of the `proxyAddresses`)
+/// * a bare sAMAccountName
+///
+/// A name may belong to more than one user (under different UPN suffixes, or in different
+/// on-premises domains), in which case it belongs to nobody.
+pub fn resolve(config: &AadConfig, name: &str) -> GraphInfoResult<UserInfo> {
+    if is_object_id(name) {
+        return azure::get_user_info(config, name);
+    }
+    if let Some(backslash) = name.find('\\') {
+        let domain = netbios_domain(config, &name[..backslash])?;
+        let users = find_by_sam_account_name(config, &name[backslash + 1..])?
+            .into_iter()
+            .filter(|u| u.domain.as_ref().is_some_and(|d| d.eq_ignore_ascii_case(domain)))
+            .collect();
+        return azure::only_one(users);
+    }
+    if name.contains('@') {
+        return resolve_address(config, name);
+    }
+
+    match find_by_short_name(config, name) {
+        Err(ref e) if e.is_not_found() &&
+                      !matches!(config.short_names, Some(ShortNamesConfig::SamAccountName)) => {
+            azure::only_one(find_by_sam_account_name(config, name)?)
+        }
+        found => found,
+    }
+}
+
+/// Find the user that goes by exactly `name` (see `present`), without trying any of the other
+/// forms of their name that `resolve` accepts.
+pub fn find_by_name(config: &AadConfig, name: &str) -> GraphInfoResult<UserInfo> {
+    let user = if name.contains('@') {
+        azure::get_user_info(config, name)?
+    } else {
+        find_by_short_name(config, name)?
+    };
+    if user.username != name {
+        return Err(GraphInfoRetrievalError::NotFound);
+    }
+    Ok(user)
+}
+
+/// Find the user with the short name `name`, under the configured `short_names` mode.
+fn find_by_short_name(config: &AadConfig, name: &str) -> GraphInfoResult<UserInfo> {
+    match config.short_names {
+        Some(ShortNamesConfig::StripSuffix { ref suffixes }) => {
+            let mut users = vec![];
+            for suffix in suffixes {
+                match azure::get_user_info(config, &format!("{}@{}", name, suffix)) {
+                    Ok(u) => users.push(u),
+                    Err(ref e) if e.is_not_found() => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            azure::only_one(users)
+        }
+        Some(ShortNamesConfig::MailNickname) => {
+            azure::only_one(azure::find_users(config,
+                                              &format!("mailNickname+eq+{}",
+                                                       azure::odata_string(name)))?)
+        }
+        Some(ShortNamesConfig::SamAccountName) => {
+            azure::only_one(find_by_sam_account_name(config, name)?)
+        }
+        None => Err(GraphInfoRetrievalError::NotFound),
+    }
+}
+
+/// Find the user with the UPN or mail address `address`.
+fn resolve_address(config: &AadConfig, address: &str) -> GraphInfoResult<UserInfo> {
+    match azure::get_user_info(config, address) {
+        Err(ref e) if e.is_not_found() => {}
+        found => return found,
+    }
+    let users = azure::find_users(config,
+                                  &format!("mail+eq+{}", azure::odata_string(address)))?;
+    if !users.is_empty() {
+        return azure::only_one(users);
+    }
+    let proxy_address = azure::odata_string(&format!("smtp:{}", address));
+    azure::only_one(azure::find_users(config,
+                                      &format!("proxyAddresses/any(p:p+eq+{})", proxy_address))?)
+}
+
+fn find_by_sam_account_name(config: &AadConfig, sam: &str) -> GraphInfoResult<Vec<UserInfo>> {
+    azure::find_users(config,
+                      &format!("onPremisesSamAccountName+eq+{}", azure::odata_string(sam)))
+}
+
+/// Find the group named `name`, which may also be an object ID, or a down-level name
+/// (`DOMAIN\group`) for NetBIOS domain names in `netbios_domains`.
+pub fn resolve_group(config: &AadConfig, name: &str) -> GraphInfoResult<GroupInfo> {
+    if is_object_id(name) {
+        return azure::get_group_info_by_object_id(config, name);
+    }
+    if let Some(backslash) = name.find('\\') {
+        // Groups in different domains may share a name, so only those in this domain count.
+        let domain = netbios_domain(config, &name[..backslash])?;
+        let display_name = azure::odata_string(&name[backslash + 1..]);
+        let groups = azure::find_groups(config, &format!("displayName+eq+{}", display_name))?;
+        let in_domain =
+            |g: &GroupInfo| g.domain.as_ref().is_some_and(|d| d.eq_ignore_ascii_case(domain));
+        return azure::only_one(groups.into_iter().filter(in_domain).collect());
+    }
+    azure::get_group_info(config, name)
+}
+
+/// The DNS name of the on-premises domain with the given NetBIOS name.
+fn netbios_domain<'a>(config: &'a AadConfig, netbios_name: &str) -> GraphInfoResult<&'a str> {
+    config
+        .netbios_domains
+        .iter()
+        .find(|&(n, _)| n.eq_ignore_ascii_case(netbios_name))
+        .map(|(_, domain)| &domain[..])
+        .ok_or(GraphInfoRetrievalError::NotFound)
+}
+
+/// Check that each of `ids`, the groups listed in the `setting` section of the configuration
+/// file, is an object ID.
+///
+/// Display names are not unique, and anyone who can create a group can choose one, so groups
+/// that grant anything are named by object ID.
+pub fn check_object_ids<'a, I>(setting: &str, ids: I) -> Result<(), String>
+    where I: IntoIterator<Item = &'a String>
+{
+    match ids.into_iter().find(|id| !is_object_id(id)) {
+        Some(id) => Err(format!("`{}` must list groups by object ID, not `{}`", setting, id)),
+        None => Ok(()),
+    }
+}
+
+/// Whether `name` looks like an object ID (a GUID).
+fn is_object_id(name: &str) -> bool {
+    name.len() == 36 &&
+    name.char_indices().all(|(i, c)| match i {
+                                8 | 13 | 18 | 23 => c == '-',
+                                _ => c.is_ascii_hexdigit(),
+                            })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_object_ids, is_object_id};
+
+    #[test]
+    fn recognizes_object_ids() {
+        assert!(is_object_id("8a4b3c2d-1e0f-4a5b-9c8d-7e6f5a4b3c2d"));
+        assert!(is_object_id("8A4B3C2D-1E0F-4A5B-9C8D-7E6F5A4B3C2D"));
+        assert!(!is_object_id("alice"));
+        assert!(!is_object_id("8a4b3c2d-1e0f-4a5b-9c8d-7e6f5a4b3c2"));
+        assert!(!is_object_id("8a4b3c2d-1e0f-4a5b-9c8d-7e6f5a4b3c2dd"));
+        assert!(!is_object_id("8a4b3c2d1e0f-4a5b-9c8d-7e6f5a4b3c2d-"));
+        assert!(!is_object_id("8a4b3c2d-1e0f-4a5b-9c8d-7e6f5a4b3c2g"));
+    }
+
+    #[test]
+    fn groups_must_be_listed_by_object_id() {
+        let ids = vec!["8a4b3c2d-1e0f-4a5b-9c8d-7e6f5a4b3c2d".to_string()];
+        assert!(check_object_ids("local_groups", &ids).is_ok());
+        let names = vec!["8a4b3c2d-1e0f-4a5b-9c8d-7e6f5a4b3c2d".to_string(),
+                         "Linux-Admins".to_string()];
+        assert!(check_object_ids("local_groups", &names).is_err());
+    }
+}
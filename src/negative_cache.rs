@@ -0,0 +1,103 @@
+//! An in-memory record of names and IDs that are not in the directory
+//!
+//! Shells, `ls` and `sudo` ask about local names and IDs over and over, and every one of those
+//! lookups would otherwise go all the way to Graph to be told the entry doesn't exist. NotFound
+//! answers are remembered here for `negative_cache_ttl` seconds, separately from (and for much
+//! less time than) any positive answers.
+
+use protocol::{Request, Response};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct NegativeCache {
+    ttl: Duration,
+    expiries: Mutex<HashMap<String, Instant>>,
+}
+
+/// Only keyed lookups of a single user or group are remembered.
+fn is_cacheable(request: &Request) -> bool {
+    matches!(*request,
+             Request::GetPwNam(_) |
+             Request::GetPwUid(_) |
+             Request::GetGrNam(_) |
+             Request::GetGrGid(_) |
+             Request::GetSpNam(_))
+}
+
+impl NegativeCache {
+    pub fn new(ttl: u64) -> NegativeCache {
+        NegativeCache {
+            ttl: Duration::from_secs(ttl),
+            expiries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `request` was recently answered with NotFound.
+    pub fn contains(&self, request: &Request) -> bool {
+        if !is_cacheable(request) {
+            return false;
+        }
+        match self.expiries.lock() {
+            Ok(expiries) => expiries.get(&request.key()).is_some_and(|&e| e > Instant::now()),
+            Err(_) => false,
+        }
+    }
+
+    /// Remember the answer to `request`, if it was NotFound.
+    pub fn store(&self, request: &Request, response: &Response) {
+        if *response != Response::NotFound || !is_cacheable(request) {
+            return;
+        }
+        if let Ok(mut expiries) = self.expiries.lock() {
+            let now = Instant::now();
+            expiries.retain(|_, &mut e| e > now);
+            expiries.insert(request.key(), now + self.ttl);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{Request, Response};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+    use super::NegativeCache;
+
+    #[test]
+    fn remembers_only_not_found() {
+        let cache = NegativeCache::new(60);
+        let alice = Request::GetPwNam("alice".to_string());
+        let bob = Request::GetPwNam("bob".to_string());
+        cache.store(&alice, &Response::NotFound);
+        cache.store(&bob, &Response::TryAgain);
+        assert!(cache.contains(&alice));
+        assert!(!cache.contains(&bob));
+        assert!(!cache.contains(&Request::GetSpNam("alice".to_string())));
+    }
+
+    #[test]
+    fn only_keyed_lookups_are_remembered() {
+        let cache = NegativeCache::new(60);
+        let lookups = [Request::InitGroups("alice".to_string()), Request::EnumeratePasswd];
+        for request in &lookups {
+            cache.store(request, &Response::NotFound);
+            assert!(!cache.contains(request));
+        }
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let cache = NegativeCache {
+            ttl: Duration::from_millis(50),
+            expiries: Mutex::new(HashMap::new()),
+        };
+        let request = Request::GetGrGid(5000);
+        cache.store(&request, &Response::NotFound);
+        assert!(cache.contains(&request));
+        thread::sleep(Duration::from_millis(100));
+        assert!(!cache.contains(&request));
+    }
+}
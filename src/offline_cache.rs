@@ -0,0 +1,286 @@
+//! A persistent record of successful answers, for use when AAD cannot be reached
+//!
+//! Every successful answer the daemon gives is remembered here, along with when it was last
+//! confirmed by the directory. If a later lookup fails because AAD (or the network) is having
+//! trouble, the remembered answer is given instead, so long as it is no older than the
+//! configured `max_staleness`. The record is kept in a file so that it survives restarts.
+
+extern crate serde_json;
+
+use fsutil;
+use protocol::{Request, Response};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Answers are considered stale after a week, unless configured otherwise.
+const DEFAULT_MAX_STALENESS: u64 = 7 * 24 * 60 * 60;
+
+/// A remembered answer is not written out again if it is unchanged and was confirmed less than
+/// this many seconds ago, so that a busy daemon isn't constantly rewriting the file.
+const REFRESH_INTERVAL: u64 = 5 * 60;
+
+/// The `offline_cache` section of the configuration file.
+#[derive(Deserialize,Debug)]
+pub struct OfflineCacheConfig {
+    file: String,
+    #[serde(default = "default_max_staleness")]
+    max_staleness: u64,
+}
+
+fn default_max_staleness() -> u64 {
+    DEFAULT_MAX_STALENESS
+}
+
+#[derive(Serialize,Deserialize,Debug)]
+struct Entry {
+    response: Response,
+    confirmed: u64, // seconds since the epoch
+}
+
+pub struct OfflineCache {
+    path: PathBuf,
+    max_staleness: u64,
+    /// Keyed by the `Request::key` of the request that each entry answers
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Counts changes to `entries`, and is only changed while holding its lock
+    generation: AtomicU64,
+    /// The generation of `entries` last written to the file. Holding this lock is what
+    /// entitles a thread to write the file.
+    written: Mutex<u64>,
+}
+
+/// The current time, in seconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl OfflineCache {
+    /// Load the cache from the file named in `config`. A missing, unreadable or untrusted file
+    /// yields an empty cache, which will replace it once there is something to remember.
+    pub fn open(config: &OfflineCacheConfig) -> OfflineCache {
+        let path = PathBuf::from(&config.file);
+        let mut contents = String::new();
+        let entries = match fsutil::open_trusted(&path)
+                  .and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => serde_json::from_str(&contents).unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+        OfflineCache {
+            path,
+            max_staleness: config.max_staleness,
+            entries: Mutex::new(entries),
+            generation: AtomicU64::new(0),
+            written: Mutex::new(0),
+        }
+    }
+
+    /// Remember `response` as the answer to `request`. Only positive answers are remembered.
+    pub fn store(&self, request: &Request, response: &Response) {
+        match *response {
+            Response::Passwd(_) |
+            Response::Group(_) |
+            Response::Groups(_) |
+            Response::Shadow(_) => {}
+            _ => return,
+        }
+        let key = request.key();
+        let generation = {
+            let mut entries = match self.entries.lock() {
+                Ok(e) => e,
+                Err(_) => return,
+            };
+
+            let now = now();
+            let unchanged = match entries.get(&key) {
+                Some(e) => e.response == *response && e.confirmed + REFRESH_INTERVAL > now,
+                None => false,
+            };
+            if unchanged {
+                return;
+            }
+            entries.insert(key,
+                           Entry {
+                               response: response.clone(),
+                               confirmed: now,
+                           });
+            let max_staleness = self.max_staleness;
+            entries.retain(|_, e| e.confirmed + max_staleness > now);
+            self.generation.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        self.save(generation);
+    }
+
+    /// Forget the answer to `request`, which the directory has just said does not exist, along
+    /// with any other remembered answers about the same user or group. A principal that has
+    /// been deleted must not come back the next time AAD cannot be reached.
+    pub fn forget(&self, request: &Request) {
+        let generation = {
+            let mut entries = match self.entries.lock() {
+                Ok(e) => e,
+                Err(_) => return,
+            };
+            let known = entries.remove(&request.key()).map(|e| e.response);
+            let mut related = related_requests(request);
+            if let Some(ref response) = known {
+                related.extend(requests_answered_by(response));
+            }
+            let before = entries.len();
+            for related in related {
+                entries.remove(&related.key());
+            }
+            if known.is_none() && entries.len() == before {
+                return;
+            }
+            self.generation.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        self.save(generation);
+    }
+
+    /// Write the entries out to the file, unless another thread has already written
+    /// `generation` or a later one.
+    ///
+    /// The entries are only locked while they are serialised, so lookups are not held up by
+    /// the disk. Threads that change the entries while a write is in progress queue up here,
+    /// and the first of them writes everything that they changed.
+    fn save(&self, generation: u64) {
+        let mut written = match self.written.lock() {
+            Ok(w) => w,
+            Err(_) => return,
+        };
+        if *written >= generation {
+            return;
+        }
+        let (contents, generation) = match self.entries.lock() {
+            Ok(entries) => (serde_json::to_vec(&*entries), self.generation.load(Ordering::SeqCst)),
+            Err(_) => return,
+        };
+
+        // The file holds the same information that the daemon hands out to anybody who asks,
+        // but there is no reason for anybody else to be reading it directly.
+        let contents = match contents {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+        #[allow(unused_variables)]
+        match fsutil::write_atomically(&self.path, &contents, 0o600) {
+            Ok(()) => *written = generation,
+            Err(e) => {
+                #[cfg(debug_assertions)]
+                println!("libnss-aad could not write offline cache {}: {:?}",
+                         self.path.display(),
+                         e);
+            }
+        }
+    }
+
+    /// Return the remembered answer to `request`, if it is not too stale.
+    pub fn fetch(&self, request: &Request) -> Option<Response> {
+        let entries = self.entries.lock().ok()?;
+        match entries.get(&request.key()) {
+            Some(e) if e.confirmed + self.max_staleness > now() => Some(e.response.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// The other requests that ask about the user or group that `request` names.
+fn related_requests(request: &Request) -> Vec<Request> {
+    match *request {
+        Request::GetPwNam(ref name) |
+        Request::GetSpNam(ref name) |
+        Request::InitGroups(ref name) => user_requests(name),
+        _ => vec![],
+    }
+}
+
+/// The requests whose answers describe the same user or group as `response`.
+fn requests_answered_by(response: &Response) -> Vec<Request> {
+    match *response {
+        Response::Passwd(ref p) => {
+            let mut requests = user_requests(&p.name);
+            requests.push(Request::GetPwUid(p.uid));
+            requests
+        }
+        Response::Shadow(ref s) => user_requests(&s.name),
+        Response::Group(ref g) => vec![Request::GetGrNam(g.name.clone()), Request::GetGrGid(g.gid)],
+        _ => vec![],
+    }
+}
+
+fn user_requests(name: &str) -> Vec<Request> {
+    vec![Request::GetPwNam(name.to_string()),
+         Request::GetSpNam(name.to_string()),
+         Request::InitGroups(name.to_string())]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{PasswdEntry, ShadowEntry};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn passwd(name: &str, uid: u32) -> Response {
+        Response::Passwd(PasswdEntry {
+                             name: name.to_string(),
+                             passwd: ".".to_string(),
+                             uid,
+                             gid: uid,
+                             gecos: String::new(),
+                             dir: format!("/home/{}", name),
+                             shell: "/bin/bash".to_string(),
+                         })
+    }
+
+    fn shadow(name: &str) -> Response {
+        Response::Shadow(ShadowEntry {
+                             name: name.to_string(),
+                             password: "*".to_string(),
+                             last_change: -1,
+                             expire: -1,
+                         })
+    }
+
+    #[test]
+    fn forgetting_a_user_forgets_everything_about_them() {
+        let config = OfflineCacheConfig {
+            file: env::temp_dir()
+                .join(format!("nss-aad-offline-cache-test.{}", process::id()))
+                .to_string_lossy()
+                .into_owned(),
+            max_staleness: DEFAULT_MAX_STALENESS,
+        };
+        let alice = Request::GetPwNam("alice@contoso.com".to_string());
+        let alice_uid = Request::GetPwUid(1001);
+        let alice_shadow = Request::GetSpNam("alice@contoso.com".to_string());
+        let alice_groups = Request::InitGroups("alice@contoso.com".to_string());
+        let bob = Request::GetPwNam("bob@contoso.com".to_string());
+
+        let cache = OfflineCache::open(&config);
+        cache.store(&alice, &passwd("alice@contoso.com", 1001));
+        cache.store(&alice_uid, &passwd("alice@contoso.com", 1001));
+        cache.store(&alice_shadow, &shadow("alice@contoso.com"));
+        cache.store(&alice_groups, &Response::Groups(vec![2000]));
+        cache.store(&bob, &passwd("bob@contoso.com", 1002));
+        assert_eq!(OfflineCache::open(&config).fetch(&alice_uid),
+                   Some(passwd("alice@contoso.com", 1001)));
+
+        cache.forget(&alice);
+        for cache in &[cache, OfflineCache::open(&config)] {
+            assert_eq!(cache.fetch(&alice), None);
+            assert_eq!(cache.fetch(&alice_uid), None);
+            assert_eq!(cache.fetch(&alice_shadow), None);
+            assert_eq!(cache.fetch(&alice_groups), None);
+            assert_eq!(cache.fetch(&bob), Some(passwd("bob@contoso.com", 1002)));
+        }
+        let _ = fs::remove_file(&config.file);
+    }
+}
@@ -0,0 +1,124 @@
+//! POSIX attributes (RFC 2307's uidNumber, loginShell and so on) stored in the directory
+//!
+//! AAD has no POSIX attributes of its own, but they can be kept in directory extension
+//! attributes, schema extensions or custom security attributes. The `posix_attributes` section
+//! of the configuration file names the attribute holding each one; where an attribute is
+//! present on a user or group, it is used in place of whatever would otherwise be made up.
+//!
+//! An attribute name may be a path, like `ext1234_posix/uidNumber` or
+//! `customSecurityAttributes/Posix/loginShell`, to reach into a complex property.
+
+extern crate serde_json;
+
+use error::{GraphInfoResult, GraphInfoRetrievalError};
+use self::serde_json::Value;
+
+/// The `posix_attributes` section of the configuration file.
+#[derive(Deserialize,Debug,Default)]
+pub struct PosixAttributesConfig {
+    #[serde(default)]
+    pub user: UserAttributes,
+    #[serde(default)]
+    pub group: GroupAttributes,
+}
+
+/// The attributes holding each of a user's POSIX attributes, if any.
+#[derive(Deserialize,Debug,Default)]
+pub struct UserAttributes {
+    #[serde(default)]
+    pub uid_number: Option<String>,
+    #[serde(default)]
+    pub gid_number: Option<String>,
+    #[serde(default)]
+    pub login_shell: Option<String>,
+    #[serde(default)]
+    pub home_directory: Option<String>,
+    #[serde(default)]
+    pub gecos: Option<String>,
+}
+
+/// The attributes holding each of a group's POSIX attributes, if any.
+#[derive(Deserialize,Debug,Default)]
+pub struct GroupAttributes {
+    #[serde(default)]
+    pub gid_number: Option<String>,
+}
+
+impl UserAttributes {
+    /// Every configured attribute.
+    pub fn all(&self) -> Vec<&str> {
+        [&self.uid_number,
+         &self.gid_number,
+         &self.login_shell,
+         &self.home_directory,
+         &self.gecos]
+                .iter()
+                .filter_map(|a| a.as_ref().map(|a| &a[..]))
+                .collect()
+    }
+}
+
+impl GroupAttributes {
+    /// Every configured attribute.
+    pub fn all(&self) -> Vec<&str> {
+        self.gid_number.iter().map(|a| &a[..]).collect()
+    }
+}
+
+/// The property to `$select` to get `attribute`: the first component of its path.
+pub fn property(attribute: &str) -> &str {
+    attribute.split('/').next().unwrap()
+}
+
+/// Whether filtering on `attribute` is an advanced query, which Graph only answers when asked
+/// for eventual consistency. Custom security attributes are the only such POSIX attributes.
+pub fn needs_advanced_query(attribute: &str) -> bool {
+    property(attribute) == "customSecurityAttributes"
+}
+
+/// The value of `attribute` on `object`, following its path.
+fn value<'v>(object: &'v Value, attribute: &str) -> &'v Value {
+    attribute.split('/').fold(object, |v, component| &v[component])
+}
+
+/// The value of a string attribute on `object`, if it is configured and present.
+pub fn string(object: &Value, attribute: &Option<String>) -> Option<String> {
+    attribute
+        .as_ref()
+        .and_then(|a| value(object, a).as_str())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+}
+
+/// The value of a numeric attribute (such as uidNumber) on `object`, if it is configured and
+/// present.
+///
+/// Values synced from on-premises AD may arrive as strings. A value that is present but not a
+/// usable ID is an error, rather than being ignored, so that the object is not silently given
+/// some other ID.
+pub fn number(object: &Value, attribute: &Option<String>) -> GraphInfoResult<Option<u32>> {
+    match attribute.as_ref() {
+        Some(a) => number_at(object, a),
+        None => Ok(None),
+    }
+}
+
+/// As `number`, for an attribute that is always configured.
+pub fn number_at(object: &Value, attribute: &str) -> GraphInfoResult<Option<u32>> {
+    let v = value(object, attribute);
+    if v.is_null() {
+        return Ok(None);
+    }
+    let n = match v.as_u64() {
+        Some(n) => n,
+        None => {
+            v.as_str()
+                .ok_or(GraphInfoRetrievalError::UnusableImmutableID)?
+                .parse::<u64>()?
+        }
+    };
+    if n > u32::MAX as u64 {
+        return Err(GraphInfoRetrievalError::UnusableImmutableID);
+    }
+    Ok(Some(n as u32))
+}
@@ -0,0 +1,149 @@
+//! The protocol spoken between the NSS plugin and nss-aad-daemon
+//!
+//! The plugin connects to the daemon's Unix socket, writes a single Request as a line of JSON,
+//! and reads back a single Response as a line of JSON. Only the daemon holds the configuration
+//! and credentials; the plugin knows nothing but the socket's location.
+
+extern crate serde;
+extern crate serde_json;
+
+use self::serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// Where the daemon listens, and the plugin connects.
+pub const SOCKET_PATH: &str = "/var/run/nss-aad/socket";
+
+/// The longest request the daemon will read. Requests are a name or an ID, so anything longer
+/// than this is not a request at all.
+pub const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+#[derive(Serialize,Deserialize,Debug)]
+pub enum Request {
+    GetPwNam(String),
+    GetPwUid(u32),
+    GetGrNam(String),
+    GetGrGid(u32),
+    GetSpNam(String),
+    /// The GIDs of the named user's supplementary groups
+    InitGroups(String),
+    /// Every user, for getpwent
+    EnumeratePasswd,
+    /// Every group, for getgrent
+    EnumerateGroup,
+}
+
+impl Request {
+    /// A string that identifies this request, for caching its answer.
+    pub fn key(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub enum Response {
+    Passwd(PasswdEntry),
+    Group(GroupEntry),
+    Groups(Vec<u32>),
+    Shadow(ShadowEntry),
+    PasswdList(Vec<PasswdEntry>),
+    GroupList(Vec<GroupEntry>),
+    /// The entry does not exist
+    NotFound,
+    /// The directory could not be queried right now
+    TryAgain,
+    /// The daemon could not be reached, or is not able to answer at all
+    Unavailable,
+}
+
+/// Everything needed to fill in a struct passwd
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct PasswdEntry {
+    pub name: String,
+    /// `x` if the password is in the shadow entry, or else `.`
+    pub passwd: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub gecos: String,
+    pub dir: String,
+    pub shell: String,
+}
+
+/// Everything needed to fill in a struct spwd
+///
+/// Dates are in days since the epoch, or -1 if unknown, as in the shadow database itself.
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct ShadowEntry {
+    pub name: String,
+    pub password: String,
+    pub last_change: i64,
+    pub expire: i64,
+}
+
+/// Everything needed to fill in a struct group
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct GroupEntry {
+    pub name: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+/// Read one newline-terminated JSON message, of at most `limit` bytes, from `stream`.
+pub fn read_message<T: Deserialize, R: Read>(stream: R, limit: u64) -> io::Result<T> {
+    let mut line = String::new();
+    let len = BufReader::new(stream.take(limit)).read_line(&mut line)?;
+    if len as u64 == limit && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("message longer than {} bytes", limit)));
+    }
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Write `message` to `stream` as one newline-terminated line of JSON.
+pub fn write_message<T: Serialize, W: Write>(mut stream: W, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::{Request, Response, read_message, write_message};
+
+    #[test]
+    fn messages_round_trip() {
+        let mut buffer = vec![];
+        write_message(&mut buffer, &Request::GetPwNam("alice".to_string())).unwrap();
+        assert_eq!(buffer.last(), Some(&b'\n'));
+        match read_message(&buffer[..], 1024).unwrap() {
+            Request::GetPwNam(name) => assert_eq!(name, "alice"),
+            r => panic!("unexpected request {:?}", r),
+        }
+    }
+
+    #[test]
+    fn message_may_fill_the_limit() {
+        let mut buffer = vec![];
+        write_message(&mut buffer, &Response::Groups(vec![1000, 1001])).unwrap();
+        let limit = buffer.len() as u64;
+        assert_eq!(read_message::<Response, _>(&buffer[..], limit).unwrap(),
+                   Response::Groups(vec![1000, 1001]));
+    }
+
+    #[test]
+    fn longer_message_is_refused() {
+        let mut buffer = vec![];
+        write_message(&mut buffer, &Request::GetPwNam("a".repeat(100))).unwrap();
+        let limit = buffer.len() as u64 - 1;
+        let err = read_message::<Request, _>(&buffer[..], limit).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn gibberish_is_refused() {
+        let err = read_message::<Request, _>(&b"GET / HTTP/1.0\r\n"[..], 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,189 @@
+//! Templates for the home directory, login shell and GECOS field of passwd entries, and
+//! per-group overrides of them
+//!
+//! A template is expanded for each user, replacing these with the user's attributes:
+//!
+//! * `%u`: the name the user goes by (see `names`)
+//! * `%U`: the user's userPrincipalName
+//! * `%d`: the user's on-premises domain, or else the suffix of their UPN
+//! * `%N`: the user's display name
+//! * `%o`: the user's office location
+//! * `%p`: the user's first business phone number
+//! * `%%`: a `%`
+//!
+//! Each field is taken from the first matching group override that sets it, or else from the
+//! user's POSIX attributes (see `posix`), or else from the template, or else from the default
+//! template.
+
+use AadConfig;
+use UserInfo;
+
+const DEFAULT_HOME: &str = "/home/%u";
+const DEFAULT_SHELL: &str = "/bin/bash";
+const DEFAULT_GECOS: &str = "%N";
+
+/// The `templates` section of the configuration file.
+#[derive(Deserialize,Debug,Default)]
+pub struct TemplatesConfig {
+    #[serde(default)]
+    home: Option<String>,
+    #[serde(default)]
+    shell: Option<String>,
+    #[serde(default)]
+    gecos: Option<String>,
+}
+
+/// An entry in the `group_overrides` list of the configuration file: templates for the members
+/// of the AAD group whose object ID is `group`.
+#[derive(Deserialize,Debug)]
+pub struct GroupOverride {
+    pub group: String,
+    #[serde(default)]
+    home: Option<String>,
+    #[serde(default)]
+    shell: Option<String>,
+    #[serde(default)]
+    gecos: Option<String>,
+}
+
+/// The templated fields of a passwd entry
+pub struct Fields {
+    pub home: String,
+    pub shell: String,
+    pub gecos: String,
+}
+
+/// Work out the templated fields of `user`'s passwd entry, given the object IDs of the groups
+/// they belong to (which are only needed if there are any group overrides).
+pub fn fields(config: &AadConfig, user: &UserInfo, groups: &[String]) -> Fields {
+    let overrides: Vec<&GroupOverride> = config
+        .group_overrides
+        .iter()
+        .filter(|o| groups.iter().any(|g| g.eq_ignore_ascii_case(&o.group)))
+        .collect();
+    let templates = &config.templates;
+    let field = |overridden: Option<&String>,
+                 attribute: &Option<String>,
+                 template: &Option<String>,
+                 default: &str| match (overridden, attribute) {
+        (Some(t), _) => expand(t, user),
+        (None, Some(a)) => sanitize(a),
+        (None, None) => expand(template.as_ref().map_or(default, |t| &t[..]), user),
+    };
+    Fields {
+        home: field(overrides.iter().filter_map(|o| o.home.as_ref()).next(),
+                    &user.home,
+                    &templates.home,
+                    DEFAULT_HOME),
+        shell: field(overrides.iter().filter_map(|o| o.shell.as_ref()).next(),
+                     &user.shell,
+                     &templates.shell,
+                     DEFAULT_SHELL),
+        gecos: field(overrides.iter().filter_map(|o| o.gecos.as_ref()).next(),
+                     &user.gecos,
+                     &templates.gecos,
+                     DEFAULT_GECOS),
+    }
+}
+
+/// Expand `template` for `user`.
+///
+/// The user's attributes are sanitized (see `sanitize`) as they are substituted.
+fn expand(template: &str, user: &UserInfo) -> String {
+    let mut expanded = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        let value = match chars.next() {
+            Some('u') => &user.username[..],
+            Some('U') => &user.upn[..],
+            Some('d') => {
+                match user.domain {
+                    Some(ref d) => &d[..],
+                    None => user.upn.rsplit('@').next().unwrap_or(""),
+                }
+            }
+            Some('N') => &user.fullname[..],
+            Some('o') => user.office.as_ref().map_or("", |o| &o[..]),
+            Some('p') => user.phone.as_ref().map_or("", |p| &p[..]),
+            Some('%') => "%",
+            Some(other) => {
+                // Not a placeholder; leave it as it is
+                expanded.push('%');
+                expanded.push(other);
+                continue;
+            }
+            None => "%",
+        };
+        expanded.push_str(&sanitize(value));
+    }
+    expanded
+}
+
+/// `value`, a directory attribute, with its colons and newlines replaced with spaces, since they
+/// would break the passwd entry's format.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+                 ':' | '\n' => ' ',
+                 c => c,
+             })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {AadConfig, UserInfo};
+    use serde_yaml;
+    use super::{expand, fields, sanitize};
+
+    fn user(domain: Option<&str>) -> UserInfo {
+        let mut user = UserInfo::test_user("alice", 1000);
+        user.domain = domain.map(|d| d.to_string());
+        user.fullname = "Alice Smith".to_string();
+        user.office = Some("Building 1".to_string());
+        user
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        assert_eq!(expand("/home/%d/%u", &user(None)), "/home/contoso.com/alice");
+        assert_eq!(expand("/home/%d/%u", &user(Some("corp.contoso.com"))),
+                   "/home/corp.contoso.com/alice");
+        assert_eq!(expand("%N,%o,%p,%U", &user(None)),
+                   "Alice Smith,Building 1,,alice@contoso.com");
+    }
+
+    #[test]
+    fn leaves_other_percent_signs() {
+        assert_eq!(expand("100%% %x %", &user(None)), "100% %x %");
+    }
+
+    #[test]
+    fn sanitizes_substituted_attributes() {
+        let mut u = user(None);
+        u.fullname = "Smith: Alice\nroot::0:0".to_string();
+        assert_eq!(expand("%N", &u), "Smith  Alice root  0 0");
+        assert_eq!(sanitize("/bin/bash"), "/bin/bash");
+        assert_eq!(sanitize("a:b\nc"), "a b c");
+    }
+
+    #[test]
+    fn overrides_apply_to_members_of_the_group_by_object_id() {
+        let config: AadConfig = serde_yaml::from_str("tenant: contoso.com\n\
+                                                      default_user_group_id: 1000\n\
+                                                      group_overrides:\n\
+                                                      - group: \
+                                                      9c6e1a48-2f5d-4b07-8d3a-e1b7c0f49a26\n  \
+                                                      shell: /usr/sbin/nologin\n")
+                .unwrap();
+        let member = ["9C6E1A48-2F5D-4B07-8D3A-E1B7C0F49A26".to_string()];
+        assert_eq!(fields(&config, &user(None), &member).shell, "/usr/sbin/nologin");
+        let other = ["restricted".to_string()];
+        assert_eq!(fields(&config, &user(None), &other).shell, "/bin/bash");
+    }
+}
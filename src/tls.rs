@@ -0,0 +1,83 @@
+//! TLS for hyper's synchronous client, using the same OpenSSL bindings as the rest of the crate
+
+extern crate hyper;
+extern crate openssl;
+
+use self::hyper::net::{HttpStream, NetworkStream, SslClient};
+use self::openssl::ssl::{HandshakeError, SslConnector, SslMethod, SslStream};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// An SslClient that verifies the server's certificate and host name against the system's
+/// trust store
+pub struct OpensslClient {
+    connector: SslConnector,
+}
+
+impl OpensslClient {
+    pub fn new() -> Result<OpensslClient, openssl::error::ErrorStack> {
+        let connector = SslConnector::builder(SslMethod::tls())?.build();
+        Ok(OpensslClient { connector })
+    }
+}
+
+impl<T> SslClient<T> for OpensslClient
+    where T: NetworkStream + Send + Clone + fmt::Debug + Sync
+{
+    type Stream = TlsStream<T>;
+
+    fn wrap_client(&self, stream: T, host: &str) -> hyper::Result<TlsStream<T>> {
+        match self.connector.connect(host, stream) {
+            Ok(stream) => Ok(TlsStream(Arc::new(Mutex::new(stream)))),
+            Err(HandshakeError::SetupFailure(e)) => Err(hyper::Error::Ssl(Box::new(e))),
+            Err(HandshakeError::Failure(s)) => Err(hyper::Error::Ssl(Box::new(s.into_error()))),
+            Err(HandshakeError::WouldBlock(s)) => Err(hyper::Error::Ssl(Box::new(s.into_error()))),
+        }
+    }
+}
+
+/// A TLS stream that hyper can clone; the clones share one connection
+pub struct TlsStream<T = HttpStream>(Arc<Mutex<SslStream<T>>>);
+
+impl<T> Clone for TlsStream<T> {
+    fn clone(&self) -> TlsStream<T> {
+        TlsStream(self.0.clone())
+    }
+}
+
+impl<T: Read + Write> Read for TlsStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl<T: Read + Write> Write for TlsStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl<T: NetworkStream> NetworkStream for TlsStream<T> {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.lock().unwrap().get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.lock().unwrap().get_mut().close(how)
+    }
+}
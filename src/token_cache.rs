@@ -0,0 +1,213 @@
+//! Process-wide cache of OAuth2 access tokens
+//!
+//! The OAuth2 endpoint issues tokens that are good for about an hour, so there is no reason to
+//! ask for a new one on every lookup. Tokens are kept here, keyed by the credentials that were
+//! used to obtain them, until shortly before they expire.
+//!
+//! If `token_cache_file` is configured, tokens are also kept in that file, so that they outlive
+//! the process (e.g. across restarts of nss-aad-daemon).
+
+extern crate serde_json;
+
+use fsutil;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tokens are treated as expired this many seconds early, so that a token is never handed out
+/// only to expire while the request using it is in flight.
+const EXPIRY_MARGIN_SECS: u64 = 300;
+
+lazy_static! {
+    static ref TOKEN_CACHE: Mutex<HashMap<String, AccessToken>> = Mutex::new(HashMap::new());
+}
+
+/// A cached token, as represented in the token cache file
+#[derive(Serialize, Deserialize, Debug)]
+struct CachedToken {
+    access_token: String,
+    expires_on: u64, // seconds since the epoch
+}
+
+type CacheFile = HashMap<String, CachedToken>;
+
+#[derive(Clone, Debug)]
+pub struct AccessToken {
+    pub token: String,
+    pub expires_on: SystemTime,
+}
+
+impl AccessToken {
+    /// Build an AccessToken from a bearer token and the `expires_in` value (in seconds) that
+    /// accompanied it.
+    pub fn new(token: String, expires_in: u64) -> AccessToken {
+        AccessToken {
+            token,
+            expires_on: SystemTime::now() + Duration::from_secs(expires_in),
+        }
+    }
+
+    /// Whether the token can still be used for a request made now.
+    pub fn is_fresh(&self) -> bool {
+        SystemTime::now() + Duration::from_secs(EXPIRY_MARGIN_SECS) < self.expires_on
+    }
+}
+
+/// Return the cached bearer token for `key`, if there is one that has not (nearly) expired.
+pub fn get(key: &str) -> Option<String> {
+    let cache = match TOKEN_CACHE.lock() {
+        Ok(c) => c,
+        Err(_) => return None, // a panicking thread shouldn't take lookups down with it
+    };
+    match cache.get(key) {
+        Some(t) if t.is_fresh() => Some(t.token.clone()),
+        _ => None,
+    }
+}
+
+/// Store `token` for later use by any thread in this process.
+///
+/// Tokens that are already stale (e.g. because the response carried no `expires_in`) are not
+/// stored.
+pub fn put(key: &str, token: AccessToken) {
+    if !token.is_fresh() {
+        return;
+    }
+    if let Ok(mut cache) = TOKEN_CACHE.lock() {
+        cache.insert(key.to_string(), token);
+    }
+}
+
+/// Read the token cache file, returning an empty cache if it is missing, unreadable, corrupt,
+/// or not owned by a user that this process trusts.
+fn read_cache_file(path: &Path) -> CacheFile {
+    let mut contents = String::new();
+    match fsutil::open_trusted(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => serde_json::from_str(&contents).unwrap_or_default(),
+        Err(_) => CacheFile::new(),
+    }
+}
+
+/// Return the token for `key` from the token cache file at `path`, if the file holds one that
+/// has not (nearly) expired.
+pub fn get_from_file(path: &str, key: &str) -> Option<AccessToken> {
+    let cached = read_cache_file(Path::new(path)).remove(key)?;
+    let token = AccessToken {
+        token: cached.access_token,
+        expires_on: UNIX_EPOCH + Duration::from_secs(cached.expires_on),
+    };
+    if token.is_fresh() { Some(token) } else { None }
+}
+
+/// Store `token` in the token cache file at `path`, so that later processes can use it.
+///
+/// Writers serialise on `<path>.lock`, and the file itself is replaced atomically so readers
+/// never need to lock. The file is readable only by its owner. Failing to write it is not an
+/// error.
+pub fn put_to_file(path: &str, key: &str, token: &AccessToken) {
+    let path = Path::new(path);
+    let expires_on = match token.expires_on.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => return,
+    };
+    let _lock = match fsutil::lock_exclusive(&path.with_extension("lock")) {
+        Ok(l) => l,
+        Err(_) => return,
+    };
+
+    let mut cache = read_cache_file(path);
+    cache.retain(|_, t| UNIX_EPOCH + Duration::from_secs(t.expires_on) > SystemTime::now());
+    cache.insert(key.to_string(),
+                 CachedToken {
+                     access_token: token.token.clone(),
+                     expires_on,
+                 });
+
+    let contents = match serde_json::to_vec(&cache) {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    #[allow(unused_variables)]
+    match fsutil::write_atomically(path, &contents, 0o600) {
+        Ok(()) => {}
+        Err(e) => {
+            #[cfg(debug_assertions)]
+            println!("libnss-aad could not write token cache {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use super::{AccessToken, get_from_file, put_to_file};
+
+    fn cache_file(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("nss-aad-token-cache-{}-test.{}", name, process::id()))
+    }
+
+    fn remove(path: &str) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(Path::new(path).with_extension("lock"));
+    }
+
+    fn seconds_from_now(secs: u64) -> u64 {
+        (SystemTime::now() + Duration::from_secs(secs))
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn writing_a_token_keeps_the_other_keys() {
+        let path = cache_file("keep");
+        let path = path.to_str().unwrap();
+        put_to_file(path, "a", &AccessToken::new("token-a".to_string(), 3600));
+        put_to_file(path, "b", &AccessToken::new("token-b".to_string(), 3600));
+
+        assert_eq!(get_from_file(path, "a").unwrap().token, "token-a");
+        assert_eq!(get_from_file(path, "b").unwrap().token, "token-b");
+        assert!(get_from_file(path, "c").is_none());
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        remove(path);
+    }
+
+    #[test]
+    fn expired_tokens_are_dropped() {
+        let path = cache_file("expiry");
+        fs::write(&path,
+                  format!("{{\"expired\":{{\"access_token\":\"old\",\"expires_on\":{}}},\
+                           \"expiring\":{{\"access_token\":\"soon\",\"expires_on\":{}}}}}",
+                          seconds_from_now(0) - 60,
+                          seconds_from_now(60)))
+                .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let path = path.to_str().unwrap();
+
+        // A token about to expire is not handed out, but is kept until it has expired.
+        assert!(get_from_file(path, "expiring").is_none());
+        put_to_file(path, "a", &AccessToken::new("token-a".to_string(), 3600));
+        let contents = fs::read_to_string(path).unwrap();
+        assert!(!contents.contains("\"expired\""));
+        assert!(contents.contains("\"expiring\""));
+        remove(path);
+    }
+
+    #[test]
+    fn untrusted_file_is_ignored() {
+        let path = cache_file("untrusted");
+        let path = path.to_str().unwrap();
+        put_to_file(path, "a", &AccessToken::new("token-a".to_string(), 3600));
+        fs::set_permissions(path, fs::Permissions::from_mode(0o666)).unwrap();
+
+        assert!(get_from_file(path, "a").is_none());
+        remove(path);
+    }
+}